@@ -1,6 +1,6 @@
 use criterion::{Criterion, criterion_group, criterion_main};
 use rapids::{
-    codecs::{BinaryCodec, NaiveCodec},
+    codecs::{BinaryCodec, CborCodec, NaiveCodec},
     types::{
         Codec, Control, ExpectedSessionState, HandshakeRequest, HandshakeResponse,
         HandshakeResponseOk, Header, ProtocolVersion, RiverResult, TransportControlMessage,
@@ -11,6 +11,7 @@ use std::hint::black_box;
 
 static BIN: BinaryCodec = BinaryCodec {};
 static NAI: NaiveCodec = NaiveCodec {};
+static CBOR: CborCodec = CborCodec {};
 
 fn criterion_benchmark(c: &mut Criterion) {
     let value = vec![
@@ -67,6 +68,7 @@ fn criterion_benchmark(c: &mut Criterion) {
             payload: Control::HandshakeResponse(HandshakeResponse {
                 status: RiverResult::<HandshakeResponseOk, String>::Ok(HandshakeResponseOk {
                     session_id: generate_id(),
+                    affinity_token: None,
                 })
                 .into(),
             }),
@@ -84,6 +86,7 @@ fn criterion_benchmark(c: &mut Criterion) {
             payload: Control::HandshakeResponse(HandshakeResponse {
                 status: RiverResult::<HandshakeResponseOk, String>::Ok(HandshakeResponseOk {
                     session_id: generate_id(),
+                    affinity_token: None,
                 })
                 .into(),
             }),
@@ -112,6 +115,14 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| black_box(rmp_serde::to_vec(&value)))
     });
 
+    c.bench_function("json round-trip msgpack encode", |b| {
+        b.iter(|| {
+            let val: serde_json::Value =
+                serde_json::from_slice(&serde_json::to_vec(&value).unwrap()).unwrap();
+            black_box(rmp_serde::to_vec(&val))
+        })
+    });
+
     c.bench_function("correct msgpack encode", |b| {
         b.iter(|| black_box(BIN.encode_to_vec(&value)))
     });
@@ -119,6 +130,10 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("json encode", |b| {
         b.iter(|| black_box(NAI.encode_to_vec(&value)))
     });
+
+    c.bench_function("cbor encode", |b| {
+        b.iter(|| black_box(CBOR.encode_to_vec(&value)))
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,143 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use futures_util::{SinkExt, StreamExt};
+use kanal::{AsyncReceiver, AsyncSender};
+use rapids::{
+    codecs::BinaryCodec,
+    dispatch::{RiverServer, ServiceHandler},
+    transport::{Frame, client::RawClient, tcp::TcpTransport},
+    types::{
+        Codec, Control, ExpectedSessionState, HandshakeRequest, Header, IncomingMessage,
+        OutgoingMessage, Payload, ProcedureRes, ProtocolVersion, RPCMetadata, RawPayload,
+        RequestInner, TransportControlMessage, TransportRequestMessage,
+    },
+    utils::{generate_id, ok_payload, payload_to_msg},
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{net::TcpListener, runtime::Runtime};
+use tokio_tungstenite::tungstenite::Message;
+
+static BIN: BinaryCodec = BinaryCodec {};
+
+struct Echo;
+
+impl ServiceHandler for Echo {
+    fn description(&self) -> HashMap<String, Vec<String>> {
+        HashMap::from([("bench".to_string(), vec!["echo".to_string()])])
+    }
+
+    async fn invoke_rpc(
+        &self,
+        _service: String,
+        _procedure: String,
+        metadata: RPCMetadata,
+        channel: AsyncSender<OutgoingMessage>,
+        payload: RawPayload,
+        _recv: AsyncReceiver<IncomingMessage>,
+    ) {
+        let value: Payload = payload.decode().unwrap();
+        let response = ProcedureRes::Response(ok_payload(value));
+
+        channel
+            .send(payload_to_msg(response, &metadata, true, false))
+            .await
+            .ok();
+    }
+}
+
+fn header(stream_id: String, control_flags: i32) -> Header {
+    Header {
+        id: generate_id(),
+        from: "bench".to_string(),
+        to: "SERVER".to_string(),
+        seq: 0,
+        ack: 0,
+        stream_id,
+        control_flags,
+    }
+}
+
+fn handshake() -> Vec<u8> {
+    BIN.encode_to_vec(&TransportControlMessage {
+        header: header(generate_id(), 0),
+        payload: Control::HandshakeRequest(HandshakeRequest {
+            protocol_version: ProtocolVersion::V2_0,
+            session_id: generate_id(),
+            expected_session_state: ExpectedSessionState::default(),
+            metadata: None,
+        }),
+    })
+    .unwrap()
+}
+
+fn rpc() -> Vec<u8> {
+    BIN.encode_to_vec(&TransportRequestMessage {
+        header: header(generate_id(), 0b1010),
+        inner: RequestInner::Init {
+            service_name: "bench".to_string(),
+            procedure_name: "echo".to_string(),
+            payload: Payload::new(&[1, 2, 3, 4]).unwrap(),
+        },
+    })
+    .unwrap()
+}
+
+fn server() -> Arc<RiverServer<Echo>> {
+    Arc::new(RiverServer::new_with_heartbeat_interval(
+        BinaryCodec {},
+        Echo,
+        Duration::ZERO,
+    ))
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    let mut ws = rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server().serve_listener(listener));
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+            .await
+            .unwrap();
+        ws.send(Message::Binary(handshake().into())).await.unwrap();
+        ws.next().await.unwrap().unwrap();
+
+        ws
+    });
+
+    let mut tcp = rt.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server().serve_tcp(listener));
+
+        let mut client = RawClient::new(
+            TcpTransport::connect(addr).await.unwrap(),
+            Arc::new(BinaryCodec {}),
+        );
+        client.handshake().await.unwrap();
+
+        client
+    });
+
+    c.bench_function("websocket rpc", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                ws.send(Message::Binary(rpc().into())).await.unwrap();
+                ws.next().await.unwrap().unwrap();
+            });
+        });
+    });
+
+    c.bench_function("tcp rpc", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                tcp.send_frame(Frame::binary(rpc())).await.unwrap();
+                tcp.recv_frame().await.unwrap().unwrap();
+            });
+        });
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
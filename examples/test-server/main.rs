@@ -5,7 +5,9 @@ use kanal::{AsyncReceiver, AsyncSender};
 use rapids::{
     codecs::BinaryCodec,
     dispatch::{RiverServer, ServiceHandler},
-    types::{IncomingMessage, OutgoingMessage, ProcedureRes, RPCMetadata},
+    types::{
+        IncomingMessage, OutgoingMessage, ProcedureError, ProcedureRes, RPCMetadata, RawPayload,
+    },
     utils,
 };
 
@@ -36,7 +38,7 @@ async fn main() -> Result<()> {
     ));
 
     let app = Router::new()
-        .route("/delta", get(|addr, ws| server.delta(addr, ws)))
+        .route("/delta", get(|parts, ws| server.delta(parts, ws)))
         .fallback(get(default_handler));
     info!("River server flowing at: ws://{}/delta", addr);
 
@@ -87,63 +89,61 @@ impl ServiceHandler for TestServiceHandler {
         procedure: String,
         metadata: RPCMetadata,
         channel: AsyncSender<OutgoingMessage>,
-        payload: serde_json::Value,
+        payload: RawPayload,
         recv: AsyncReceiver<IncomingMessage>,
     ) {
         match service.as_str() {
             "adder" => {
-                let service = self.service_map.adder.clone();
-                tokio::spawn(async move {
-                    let result = match procedure.as_str() {
-                        "add" => service
-                            .add(payload, &metadata)
-                            .await
-                            .map(ProcedureRes::Response),
-                        "resetCount" => service
-                            .reset_count(payload, &metadata)
-                            .await
-                            .map(ProcedureRes::Response),
-                        "uploadAdd" => service
-                            .upload_add(payload, recv, &metadata)
-                            .await
-                            .map(ProcedureRes::Response),
-                        "streamAdd" => service
-                            .stream_add(payload, recv, channel.clone(), &metadata)
-                            .await
-                            .map(|_| ProcedureRes::Close),
-                        "subscriptionAdd" => service
-                            .subscription_add(payload, channel.clone(), &metadata)
-                            .await
-                            .map(|_| ProcedureRes::Close),
-                        _ => {
-                            unreachable!(
-                                "Dispatcher guarantees only correct procedures are passed along"
-                            )
-                        }
-                    };
-
-                    let message = match &result {
-                        Ok(ProcedureRes::Response(result)) => ProcedureRes::Response(
-                            serde_json::json!({ "ok": true, "payload": result }),
-                        ),
-                        Err(err) => ProcedureRes::Response(serde_json::json!({
-                            "ok": false,
-                            "payload": {"code": "UNCAUGHT_ERROR", "message": err.to_string()}
-                        })),
-
-                        Ok(ProcedureRes::Close) => ProcedureRes::Close,
-                    };
-
-                    channel
-                        .send(utils::payload_to_msg(
-                            message,
-                            &metadata,
-                            result.is_ok(),
-                            result.is_err(),
-                        ))
+                let service = &self.service_map.adder;
+                let result = match procedure.as_str() {
+                    "add" => service
+                        .add(payload, &metadata)
                         .await
-                        .expect("TODO: handle this");
-                });
+                        .map(ProcedureRes::Response),
+                    "resetCount" => service
+                        .reset_count(payload, &metadata)
+                        .await
+                        .map(ProcedureRes::Response),
+                    "uploadAdd" => service
+                        .upload_add(payload, recv, &metadata)
+                        .await
+                        .map(ProcedureRes::Response),
+                    "streamAdd" => service
+                        .stream_add(payload, recv, channel.clone(), &metadata)
+                        .await
+                        .map(|_| ProcedureRes::Close),
+                    "subscriptionAdd" => service
+                        .subscription_add(payload, channel.clone(), &metadata)
+                        .await
+                        .map(|_| ProcedureRes::Close),
+                    _ => {
+                        unreachable!(
+                            "Dispatcher guarantees only correct procedures are passed along"
+                        )
+                    }
+                };
+
+                let message = match &result {
+                    Ok(ProcedureRes::Response(result)) => {
+                        ProcedureRes::Response(utils::ok_payload(result.clone()))
+                    }
+                    Err(err) => ProcedureRes::Response(utils::error_payload(
+                        &ProcedureError::UncaughtError,
+                        err.to_string(),
+                    )),
+
+                    Ok(ProcedureRes::Close) => ProcedureRes::Close,
+                };
+
+                channel
+                    .send(utils::payload_to_msg(
+                        message,
+                        &metadata,
+                        result.is_ok(),
+                        result.is_err(),
+                    ))
+                    .await
+                    .expect("TODO: handle this");
             }
             _ => {
                 unreachable!("Dispatcher guarantees only correct services are passed along")
@@ -2,11 +2,17 @@ use std::sync::atomic::{AtomicI64, Ordering};
 
 use kanal::{AsyncReceiver, AsyncSender};
 
-use rapids::types::{IncomingMessage, OutgoingMessage, RPCMetadata};
+use rapids::types::{IncomingMessage, OutgoingMessage, Payload, RPCMetadata, RawPayload};
+use serde::Deserialize;
 
 use super::ServiceImpl;
 use anyhow::format_err;
 
+#[derive(Deserialize)]
+struct AddRequest {
+    n: i64,
+}
+
 pub struct Service {
     state: AtomicI64,
 }
@@ -22,20 +28,14 @@ impl ServiceImpl for Service {
 impl Service {
     pub async fn add(
         &self,
-        payload: serde_json::Value,
+        payload: RawPayload,
         _metadata: &RPCMetadata,
-    ) -> anyhow::Result<serde_json::Value> {
-        let amt = payload
-            .as_object()
-            .unwrap()
-            .get("n")
-            .unwrap()
-            .as_i64()
-            .unwrap();
+    ) -> anyhow::Result<Payload> {
+        let AddRequest { n: amt } = payload.decode()?;
 
         let res = self.state.fetch_add(amt, Ordering::SeqCst) + amt;
 
-        let return_payload = serde_json::json!({"result": res });
+        let return_payload = serde_json::json!({"result": res }).into();
         if amt == 6 {
             return Err(format_err!("test"));
         }
@@ -45,65 +45,57 @@ impl Service {
 
     pub async fn reset_count(
         &self,
-        payload: serde_json::Value,
+        payload: RawPayload,
         _metadata: &RPCMetadata,
-    ) -> anyhow::Result<serde_json::Value> {
-        let amt = payload.as_number().unwrap().as_i64().unwrap();
+    ) -> anyhow::Result<Payload> {
+        let amt: i64 = payload.decode()?;
 
         self.state.store(amt, Ordering::SeqCst);
 
-        let return_payload = serde_json::json!(null);
+        let return_payload = Payload::null();
 
         Ok(return_payload)
     }
 
     pub async fn upload_add(
         &self,
-        _: serde_json::Value,
+        _: RawPayload,
         recv: AsyncReceiver<IncomingMessage>,
         _metadata: &RPCMetadata,
-    ) -> anyhow::Result<serde_json::Value> {
+    ) -> anyhow::Result<Payload> {
         // TODO: deal with force close
         while let Ok(IncomingMessage::Request(value)) = recv.recv().await {
-            let amt = value
-                .as_object()
-                .unwrap()
-                .get("n")
-                .unwrap()
-                .as_i64()
-                .unwrap();
+            let AddRequest { n: amt } = value.decode()?;
 
             self.state.fetch_add(amt, Ordering::SeqCst);
         }
 
         let result = self.state.load(Ordering::SeqCst);
 
-        let return_payload = serde_json::json!({ "result": result });
+        let return_payload = serde_json::json!({ "result": result }).into();
 
         Ok(return_payload)
     }
 
     pub async fn stream_add(
         &self,
-        _: serde_json::Value,
+        _: RawPayload,
         recv: AsyncReceiver<IncomingMessage>,
         send: AsyncSender<OutgoingMessage>,
         metadata: &RPCMetadata,
     ) -> anyhow::Result<()> {
         // TODO: deal with force close
         while let Ok(IncomingMessage::Request(value)) = recv.recv().await {
-            let amt = value
-                .as_object()
-                .unwrap()
-                .get("n")
-                .unwrap()
-                .as_i64()
-                .unwrap();
+            let AddRequest { n: amt } = value.decode()?;
 
             let res = self.state.fetch_add(amt, Ordering::SeqCst) + amt;
 
-            Self::send_payload(send.clone(), serde_json::json!({ "result": res }), metadata)
-                .await?;
+            Self::send_payload(
+                send.clone(),
+                serde_json::json!({ "result": res }).into(),
+                metadata,
+            )
+            .await?;
         }
 
         Ok(())
@@ -111,18 +103,21 @@ impl Service {
 
     pub async fn subscription_add(
         &self,
-        payload: serde_json::Value,
+        payload: RawPayload,
         send: AsyncSender<OutgoingMessage>,
         metadata: &RPCMetadata,
     ) -> anyhow::Result<()> {
-        let amts = payload.as_array().unwrap();
+        let amts: Vec<i64> = payload.decode()?;
 
         for amt in amts {
-            let amt = amt.as_i64().unwrap();
             let res = self.state.fetch_add(amt, Ordering::SeqCst) + amt;
 
-            Self::send_payload(send.clone(), serde_json::json!({ "result": res }), metadata)
-                .await?;
+            Self::send_payload(
+                send.clone(),
+                serde_json::json!({ "result": res }).into(),
+                metadata,
+            )
+            .await?;
         }
 
         Ok(())
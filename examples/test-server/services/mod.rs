@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use kanal::AsyncSender;
 use rapids::{
-    types::{OutgoingMessage, ProcedureRes, RPCMetadata},
+    types::{OutgoingMessage, Payload, ProcedureRes, RPCMetadata},
     utils,
 };
 #[allow(unused_imports)]
@@ -39,10 +39,10 @@ pub trait ServiceImpl {
 
     async fn send_payload(
         channel: AsyncSender<OutgoingMessage>,
-        payload: serde_json::Value,
+        payload: Payload,
         metadata: &RPCMetadata,
     ) -> anyhow::Result<()> {
-        let message = serde_json::json!({ "ok": true, "payload": payload });
+        let message = utils::ok_payload(payload);
 
         channel
             .send(utils::payload_to_msg(
@@ -5,22 +5,54 @@
 //! # Built-in codecs
 //! - JSON: [`NaiveCodec`]
 //! - MessagePack: [`BinaryCodec`]
+//! - CBOR: [`CborCodec`]
 
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, sync::Arc};
 
-use crate::types::Codec;
+use anyhow::{Result, format_err};
+use rmp::Marker;
+use serde::{Deserialize, Serialize, de::DeserializeSeed};
+use serde_json::value::RawValue;
+
+use crate::types::{Codec, Control, Header, RawFrame, TransportCodec};
 
 /// Basic codec that encodes messages as JSON using [`serde_json`]
+///
+/// Clients using this codec may send WebSocket text frames instead of
+/// binary frames, which makes it handy for debugging with generic
+/// WebSocket tools.
 #[derive(Clone, Copy)]
 pub struct NaiveCodec {}
 
 impl Codec for NaiveCodec {
-    fn decode_slice<'a, T>(&self, v: &'a [u8]) -> Result<T>
+    fn decode_seed<'a, S>(&self, v: &'a [u8], seed: S) -> Result<S::Value>
     where
-        T: Deserialize<'a>,
+        S: DeserializeSeed<'a>,
     {
-        Ok(serde_json::from_slice(v)?)
+        let mut deserializer = serde_json::Deserializer::from_slice(v);
+        let value = seed.deserialize(&mut deserializer)?;
+        deserializer.end()?;
+
+        Ok(value)
+    }
+
+    fn decode_frame<'a>(&self, v: &'a [u8]) -> Result<RawFrame<'a>> {
+        let frame: JsonFrame<'a> = self.decode_slice(v)?;
+
+        Ok(RawFrame {
+            header: Header {
+                id: frame.id,
+                from: frame.from,
+                to: frame.to,
+                seq: frame.seq,
+                ack: frame.ack,
+                stream_id: frame.stream_id,
+                control_flags: frame.control_flags,
+            },
+            service_name: frame.service_name,
+            procedure_name: frame.procedure_name,
+            payload: Cow::Borrowed(frame.payload.get().as_bytes()),
+        })
     }
 
     fn encode_to_vec<T>(&self, value: &T) -> Result<Vec<u8>>
@@ -29,70 +61,381 @@ impl Codec for NaiveCodec {
     {
         Ok(serde_json::to_vec(value)?)
     }
+
+    fn is_text(&self) -> bool {
+        true
+    }
+}
+
+/// Message as read by [`NaiveCodec::decode_frame`](Codec::decode_frame)
+///
+/// The header is listed out rather than flattened, as `#[serde(flatten)]`
+/// buffers fields in a way that [`RawValue`] doesn't support.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonFrame<'a> {
+    id: String,
+    from: String,
+    to: String,
+    seq: i32,
+    ack: i32,
+    stream_id: String,
+    control_flags: i32,
+    service_name: Option<String>,
+    procedure_name: Option<String>,
+    #[serde(borrow)]
+    payload: &'a RawValue,
 }
 
 /// Codec that encodes messages into MessagePack using [`rmp_serde`]
+///
+/// Structs are encoded as maps keyed by field name, which is what the
+/// TypeScript River implementation expects. [`rmp_serde`] encodes structs
+/// as arrays by default, so [`rmp_serde::to_vec`] can't be used directly.
 #[derive(Clone, Copy)]
 pub struct BinaryCodec {}
 
 impl Codec for BinaryCodec {
-    fn decode_slice<'a, T>(&self, v: &'a [u8]) -> Result<T>
+    fn decode_seed<'a, S>(&self, v: &'a [u8], seed: S) -> Result<S::Value>
     where
-        T: Deserialize<'a>,
+        S: DeserializeSeed<'a>,
     {
-        Ok(rmp_serde::from_slice(v)?)
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(v);
+
+        Ok(seed.deserialize(&mut deserializer)?)
+    }
+
+    fn decode_frame<'a>(&self, v: &'a [u8]) -> Result<RawFrame<'a>> {
+        let (entries, mut rest) = msgpack_map_len(v)?;
+        let mut frame = MsgpackFrame::default();
+
+        for _ in 0..entries {
+            let (key, after_key) = rest.split_at(msgpack_value_len(rest)?);
+            let (value, after_value) = after_key.split_at(msgpack_value_len(after_key)?);
+            rest = after_value;
+
+            match self.decode_slice::<&str>(key)? {
+                "id" => frame.id = Some(self.decode_slice(value)?),
+                "from" => frame.from = Some(self.decode_slice(value)?),
+                "to" => frame.to = Some(self.decode_slice(value)?),
+                "seq" => frame.seq = Some(self.decode_slice(value)?),
+                "ack" => frame.ack = Some(self.decode_slice(value)?),
+                "streamId" => frame.stream_id = Some(self.decode_slice(value)?),
+                "controlFlags" => frame.control_flags = Some(self.decode_slice(value)?),
+                "serviceName" => frame.service_name = self.decode_slice(value)?,
+                "procedureName" => frame.procedure_name = self.decode_slice(value)?,
+                "payload" => frame.payload = Some(value),
+                _ => {}
+            }
+        }
+
+        frame.into_raw()
     }
 
     fn encode_to_vec<T>(&self, value: &T) -> Result<Vec<u8>>
     where
         T: ?Sized + Serialize,
     {
-        // This is an awful solution but rmp_serde doesn't encode enum's correctly on its own.
-        // TODO: better solution? report bug to rmp_serde devs?
-        let val: serde_json::Value = serde_json::from_slice(&serde_json::to_vec(value)?)?;
-        Ok(rmp_serde::to_vec(&val)?)
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+}
+
+/// Fields collected by [`BinaryCodec::decode_frame`](Codec::decode_frame)
+#[derive(Default)]
+struct MsgpackFrame<'a> {
+    id: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    seq: Option<i32>,
+    ack: Option<i32>,
+    stream_id: Option<String>,
+    control_flags: Option<i32>,
+    service_name: Option<String>,
+    procedure_name: Option<String>,
+    payload: Option<&'a [u8]>,
+}
+
+impl<'a> MsgpackFrame<'a> {
+    fn into_raw(self) -> Result<RawFrame<'a>> {
+        fn required<T>(field: Option<T>, name: &str) -> Result<T> {
+            field.ok_or_else(|| format_err!("missing field `{name}`"))
+        }
+
+        Ok(RawFrame {
+            header: Header {
+                id: required(self.id, "id")?,
+                from: required(self.from, "from")?,
+                to: required(self.to, "to")?,
+                seq: required(self.seq, "seq")?,
+                ack: required(self.ack, "ack")?,
+                stream_id: required(self.stream_id, "streamId")?,
+                control_flags: required(self.control_flags, "controlFlags")?,
+            },
+            service_name: self.service_name,
+            procedure_name: self.procedure_name,
+            payload: Cow::Borrowed(required(self.payload, "payload")?),
+        })
     }
 }
 
-/// An enum that represents any built-in codec
+/// Reads the header of a MessagePack map, returning its length and the bytes after it
+fn msgpack_map_len(v: &[u8]) -> Result<(usize, &[u8])> {
+    let (&marker, mut rest) = v.split_first().ok_or_else(unexpected_eof)?;
+
+    let len = match Marker::from_u8(marker) {
+        Marker::FixMap(len) => usize::from(len),
+        Marker::Map16 => take_len(&mut rest, 2)?,
+        Marker::Map32 => take_len(&mut rest, 4)?,
+        marker => return Err(format_err!("Expected a map, found {marker:?}")),
+    };
+
+    Ok((len, rest))
+}
+
+/// Returns how many bytes the MessagePack value at the start of `v` takes up
 ///
-/// ⚠️ This enum will likely be deprecated and removed in the
-/// future.
+/// The value is skipped over without being decoded.
+fn msgpack_value_len(v: &[u8]) -> Result<usize> {
+    let mut rest = v;
+    let mut remaining: usize = 1;
+
+    while remaining > 0 {
+        remaining -= 1;
+
+        let (&marker, tail) = rest.split_first().ok_or_else(unexpected_eof)?;
+        rest = tail;
+
+        let data_len = match Marker::from_u8(marker) {
+            Marker::FixPos(_) | Marker::FixNeg(_) | Marker::Null | Marker::True | Marker::False => {
+                0
+            }
+            Marker::U8 | Marker::I8 => 1,
+            Marker::U16 | Marker::I16 | Marker::FixExt1 => 2,
+            Marker::FixExt2 => 3,
+            Marker::U32 | Marker::I32 | Marker::F32 => 4,
+            Marker::FixExt4 => 5,
+            Marker::U64 | Marker::I64 | Marker::F64 => 8,
+            Marker::FixExt8 => 9,
+            Marker::FixExt16 => 17,
+            Marker::FixStr(len) => usize::from(len),
+            Marker::Str8 | Marker::Bin8 => take_len(&mut rest, 1)?,
+            Marker::Str16 | Marker::Bin16 => take_len(&mut rest, 2)?,
+            Marker::Str32 | Marker::Bin32 => take_len(&mut rest, 4)?,
+            // Extension lengths don't include the type byte
+            Marker::Ext8 => take_len(&mut rest, 1)? + 1,
+            Marker::Ext16 => take_len(&mut rest, 2)? + 1,
+            Marker::Ext32 => take_len(&mut rest, 4)? + 1,
+            Marker::FixArray(len) => {
+                remaining += usize::from(len);
+                0
+            }
+            Marker::Array16 => {
+                remaining += take_len(&mut rest, 2)?;
+                0
+            }
+            Marker::Array32 => {
+                remaining += take_len(&mut rest, 4)?;
+                0
+            }
+            Marker::FixMap(len) => {
+                remaining += 2 * usize::from(len);
+                0
+            }
+            Marker::Map16 => {
+                remaining += 2 * take_len(&mut rest, 2)?;
+                0
+            }
+            Marker::Map32 => {
+                remaining += 2 * take_len(&mut rest, 4)?;
+                0
+            }
+            Marker::Reserved => return Err(format_err!("Invalid MessagePack marker")),
+        };
+
+        rest = rest.get(data_len..).ok_or_else(unexpected_eof)?;
+    }
+
+    Ok(v.len() - rest.len())
+}
+
+/// Takes a big endian length of `width` bytes from the front of `v`
+fn take_len(v: &mut &[u8], width: usize) -> Result<usize> {
+    let (bytes, rest) = v.split_at_checked(width).ok_or_else(unexpected_eof)?;
+    *v = rest;
+
+    Ok(bytes
+        .iter()
+        .fold(0, |len, &byte| (len << 8) | usize::from(byte)))
+}
+
+fn unexpected_eof() -> anyhow::Error {
+    format_err!("Unexpected end of message")
+}
+
+/// Codec that encodes messages as CBOR using [`ciborium`]
 ///
-/// # Why does this exist?
-/// The [`Codec`] trait does not supporting dyn boxing, so this
-/// enum is provided as a convenience for codec-agnostic purposes
-/// that cannot use a generic.
+/// Like [`BinaryCodec`], structs are encoded as maps keyed by field name.
+/// Messages are decoded into an owned value first, as `ciborium` can only
+/// decode owned types, so strings and byte slices are never borrowed.
 ///
-/// Use of this enum is not recommended for other libraries
-/// because 3rd party codec implementations don't work with
-/// DynCodec.
+/// This codec uses the default [`Codec::decode_frame`], so payloads are
+/// decoded and encoded again before being handed to procedures.
 #[derive(Clone, Copy)]
-pub enum DynCodec {
-    /// Binary codec
-    Binary(BinaryCodec),
-    /// Naive (JSON) codec
-    Naive(NaiveCodec),
-}
+pub struct CborCodec {}
 
-impl Codec for DynCodec {
-    fn decode_slice<'a, T>(&self, v: &'a [u8]) -> Result<T>
+impl Codec for CborCodec {
+    fn decode_seed<'a, S>(&self, v: &'a [u8], seed: S) -> Result<S::Value>
     where
-        T: Deserialize<'a>,
+        S: DeserializeSeed<'a>,
     {
-        match self {
-            DynCodec::Binary(binary_codec) => binary_codec.decode_slice(v),
-            DynCodec::Naive(naive_codec) => naive_codec.decode_slice(v),
+        let mut rest = v;
+        let value: rmpv::Value = ciborium::from_reader(&mut rest)?;
+
+        if !rest.is_empty() {
+            return Err(format_err!("Trailing bytes after CBOR message"));
         }
+
+        Ok(seed.deserialize(value)?)
     }
 
     fn encode_to_vec<T>(&self, value: &T) -> Result<Vec<u8>>
     where
         T: ?Sized + Serialize,
     {
-        match self {
-            DynCodec::Binary(binary_codec) => binary_codec.encode_to_vec(value),
-            DynCodec::Naive(naive_codec) => naive_codec.encode_to_vec(value),
+        let mut data = Vec::new();
+        ciborium::into_writer(value, &mut data)?;
+
+        Ok(data)
+    }
+}
+
+/// Looks up a built-in codec by name, for picking a codec from configuration
+///
+/// | Name | Codec |
+/// | --- | --- |
+/// | `json` | [`NaiveCodec`] |
+/// | `msgpack` | [`BinaryCodec`] |
+/// | `cbor` | [`CborCodec`] |
+///
+/// Custom codecs can be used in the same places by wrapping them in an
+/// [`Arc`] directly.
+pub fn by_name(name: &str) -> Option<Arc<dyn TransportCodec>> {
+    match name {
+        "json" => Some(Arc::new(NaiveCodec {})),
+        "msgpack" => Some(Arc::new(BinaryCodec {})),
+        "cbor" => Some(Arc::new(CborCodec {})),
+        _ => None,
+    }
+}
+
+/// Rules for picking a codec for each connection
+///
+/// By default a [`RiverServer`](crate::dispatch::RiverServer) uses the
+/// same codec for every client. With a negotiation configured, each
+/// connection can pick one of the registered codecs instead, which lets
+/// browser debugging tools speaking JSON and production clients speaking
+/// MessagePack share one endpoint.
+///
+/// Codecs are picked using the first method that finds one, in this order:
+/// 1. [WebSocket subprotocol](Self::subprotocols), matched against codec names
+/// 2. [Query parameter](Self::query_param) on the upgrade request
+/// 3. [Sniffing](Self::sniff) the handshake frame
+///
+/// If none of them find a codec the server's default codec is used.
+#[derive(Clone, Default)]
+pub struct CodecNegotiation {
+    codecs: Vec<(String, Arc<dyn TransportCodec>)>,
+    subprotocols: bool,
+    query_param: Option<String>,
+    sniff: bool,
+}
+
+impl CodecNegotiation {
+    /// Creates a negotiation with no codecs and no methods enabled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a codec that clients can pick using `name`
+    #[must_use]
+    pub fn codec(mut self, name: impl Into<String>, codec: impl TransportCodec + 'static) -> Self {
+        self.codecs.push((name.into(), Arc::new(codec)));
+        self
+    }
+
+    /// Picks the codec named by the `Sec-WebSocket-Protocol` header
+    ///
+    /// If the client requests several codecs, the one registered first is
+    /// accepted and echoed back to the client.
+    #[must_use]
+    pub fn subprotocols(mut self) -> Self {
+        self.subprotocols = true;
+        self
+    }
+
+    /// Picks the codec named by the query parameter `param`, e.g. `/delta?codec=json`
+    #[must_use]
+    pub fn query_param(mut self, param: impl Into<String>) -> Self {
+        self.query_param = Some(param.into());
+        self
+    }
+
+    /// Picks the first registered codec that can decode the handshake frame
+    ///
+    /// Codecs are tried in the order they were registered.
+    #[must_use]
+    pub fn sniff(mut self) -> Self {
+        self.sniff = true;
+        self
+    }
+
+    fn named(&self, name: &str) -> Option<Arc<dyn TransportCodec>> {
+        self.codecs
+            .iter()
+            .find(|(codec_name, _)| codec_name == name)
+            .map(|(_, codec)| codec.clone())
+    }
+
+    /// Names of the subprotocols that should be offered during the upgrade
+    pub(crate) fn offered_subprotocols(&self) -> Vec<String> {
+        if self.subprotocols {
+            self.codecs.iter().map(|(name, _)| name.clone()).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Picks a codec based on the upgrade request
+    pub(crate) fn select(
+        &self,
+        subprotocol: Option<&str>,
+        query: Option<&str>,
+    ) -> Option<Arc<dyn TransportCodec>> {
+        if let Some(codec) = subprotocol.and_then(|name| self.named(name)) {
+            return Some(codec);
         }
+
+        let param = self.query_param.as_deref()?;
+        query?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == param)
+            .and_then(|(_, name)| self.named(name))
+    }
+
+    /// Picks a codec based on the handshake frame, if sniffing is enabled
+    pub(crate) fn sniff_handshake(&self, frame: &[u8]) -> Option<Arc<dyn TransportCodec>> {
+        if !self.sniff {
+            return None;
+        }
+
+        self.codecs
+            .iter()
+            .find(|(_, codec)| {
+                codec
+                    .decode_control(frame)
+                    .is_ok_and(|message| matches!(message.payload, Control::HandshakeRequest(_)))
+            })
+            .map(|(_, codec)| codec.clone())
     }
 }
@@ -0,0 +1,35 @@
+//! Compatibility with older River protocol versions
+//!
+//! The dispatcher works with v2.0 messages internally. Clients that
+//! handshake with an older version, which compatibility has been enabled
+//! for, have their messages translated on the way in and out.
+//!
+//! # v1.1
+//! v1.1 messages have the same layout as v2.0 messages, the differences are:
+//! - Streams are closed with `0b0100`, which v2.0 uses for cancellation,
+//!   rather than `0b1000`
+//! - Streams can't be cancelled, cancellations are sent to v1.1 clients as
+//!   a close with an error payload
+//! - Handshakes may leave out `expectedSessionState`
+
+const STREAM_CANCEL_BIT: i32 = 0b0100;
+const STREAM_CLOSED_BIT: i32 = 0b1000;
+const STREAM_CLOSED_BIT_V1_1: i32 = 0b0100;
+
+/// Translates the control flags of a message received from a v1.1 client
+pub(crate) fn control_flags_from_v1_1(flags: i32) -> i32 {
+    if flags & STREAM_CLOSED_BIT_V1_1 == 0 {
+        flags
+    } else {
+        (flags & !STREAM_CLOSED_BIT_V1_1) | STREAM_CLOSED_BIT
+    }
+}
+
+/// Translates the control flags of a message being sent to a v1.1 client
+pub(crate) fn control_flags_to_v1_1(flags: i32) -> i32 {
+    if flags & (STREAM_CANCEL_BIT | STREAM_CLOSED_BIT) == 0 {
+        flags
+    } else {
+        (flags & !(STREAM_CANCEL_BIT | STREAM_CLOSED_BIT)) | STREAM_CLOSED_BIT_V1_1
+    }
+}
@@ -3,42 +3,159 @@
 //! # Setup
 //! Please refer to the `test-server` example for how to use [`ServiceHandler`] and [`RiverServer`].
 //!
+//! [`RiverServer::serve`] binds to an address and serves WebSocket clients on
+//! its own. To serve River alongside other routes in an existing axum app,
+//! route to [`RiverServer::delta`] instead.
+//!
+//! Connections over anything other than a WebSocket are served with
+//! [`RiverServer::serve_transport`], see the [`transport`](crate::transport) module.
+//!
 //! More documentation will be written in the future.
 // TODO: Real docs!!!!
 
+mod builder;
+mod sessions;
+
+pub use builder::{AffinityHook, HandshakeHook, RiverServerBuilder, SessionHook, UpgradeHook};
+
 use crate::{
+    codecs::CodecNegotiation,
+    compat,
+    limits::{ConcurrencyLimit, ConcurrencySlot, RateLimit, RateLimitAction},
+    sessions::{BufferLimit, MemorySessionStore, SessionState, SessionStore},
+    transport::{CloseReason, Frame, Transport, websocket::WebSocketTransport},
     types::{
-        Codec, Control, HandshakeError, HandshakeRequest, HandshakeResponse, HandshakeResponseOk,
-        Header, HeaderID, IncomingMessage, OutgoingMessage, RPCMetadata, RequestInner, RiverResult,
-        SimpleOutgoingMessage, StreamInfo, TransportControlMessage, TransportRequestMessage,
+        Control, HandshakeError, HandshakeRequest, HandshakeResponse, HandshakeResponseOk, Header,
+        IncomingMessage, OutgoingMessage, ProcedureError, ProcedureRes, ProtocolVersion,
+        RPCMetadata, RawPayload, RiverResult, SimpleOutgoingMessage, StreamInfo, TransportCodec,
+        TransportControlMessage, TransportRequestMessage,
     },
-    utils::generate_id,
+    utils::{error_payload, generate_id, payload_to_msg},
 };
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
 use axum::{
+    Router,
     body::Bytes,
-    extract::{
-        ConnectInfo,
-        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+    extract::{ConnectInfo, ws::WebSocketUpgrade},
+    http::{
+        StatusCode,
+        header::{COOKIE, ORIGIN},
+        request::Parts,
     },
-    response::Response,
+    response::{IntoResponse, Response},
+    routing::get,
 };
 
 use kanal::{AsyncReceiver, AsyncSender};
-use tokio::time::{self, MissedTickBehavior};
-use tracing::{Instrument, Span, info_span};
+use tokio::{
+    net::{TcpListener, ToSocketAddrs},
+    sync::mpsc,
+    task::AbortHandle,
+    time::{self, Instant},
+};
+use tracing::{Instrument, info_span};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn};
 
 /// River Server dispatch required across all clients
-pub struct RiverServer<H: ServiceHandler + 'static, C: Codec + 'static> {
-    codec: C,
+pub struct RiverServer<H: ServiceHandler + 'static> {
+    codec: Arc<dyn TransportCodec>,
     service_handler: H,
     service_description: HashMap<String, Vec<String>>,
     heartbeat_interval: Duration,
+    default_timeout: Option<Duration>,
+    service_timeouts: HashMap<String, Duration>,
+    procedure_timeouts: HashMap<(String, String), Duration>,
+    max_streams_per_session: Option<usize>,
+    procedure_limits: HashMap<(String, String), ConcurrencyLimit>,
+    rate_limit: Option<RateLimit>,
+    codec_negotiation: Option<CodecNegotiation>,
+    v1_1_compatibility: bool,
+    max_frame_size: Option<usize>,
+    default_max_payload_size: Option<usize>,
+    procedure_max_payload_sizes: HashMap<(String, String), usize>,
+    server_id: String,
+    heartbeats_until_dead: Option<u32>,
+    handshake_timeout: Duration,
+    session_grace_period: Duration,
+    session_buffer_limit: BufferLimit,
+    outgoing_channel_capacity: Option<usize>,
+    stream_channel_capacity: Option<usize>,
+    hooks: builder::Hooks,
+    sessions: sessions::OwnedSessions,
+    session_store: Arc<dyn SessionStore>,
+}
+
+/// A WebSocket upgrade request, see [`RiverServerBuilder::on_upgrade`]
+#[derive(Clone, Debug)]
+pub struct UpgradeRequest {
+    /// Address the client connected from
+    pub addr: SocketAddr,
+    /// Method, URI, headers and extensions of the request
+    pub parts: Parts,
+}
+
+impl UpgradeRequest {
+    /// Query string of the request, if any
+    pub fn query(&self) -> Option<&str> {
+        self.parts.uri.query()
+    }
+
+    /// The `Origin` header, sent by browsers with the page that opened the WebSocket
+    pub fn origin(&self) -> Option<&str> {
+        self.parts.headers.get(ORIGIN)?.to_str().ok()
+    }
+
+    /// The value of the cookie called `name`, if the request has it
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.parts
+            .headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|header| header.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find_map(|(key, value)| (key == name).then_some(value))
+    }
+}
+
+/// A client session that has completed its handshake
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    /// The id the client identified itself with
+    pub client_id: String,
+    /// The session id the client handshook with
+    pub session_id: String,
+    /// Describes where the client is connected from, like its address
+    pub peer: String,
+    /// The protocol version the client handshook with
+    pub protocol_version: ProtocolVersion,
+}
+
+/// State of a connection that has completed its handshake
+struct Connection {
+    codec: Arc<dyn TransportCodec>,
+    /// Whether messages are sent as text frames
+    text_frames: bool,
+    session: SessionInfo,
+    claim: sessions::SessionClaim,
+    /// State of the session the client resumed, if any
+    resumed: Option<SessionState>,
+}
+
+/// Concurrency slots held by a running procedure
+struct ProcedureSlots {
+    _session: ConcurrencySlot,
+    _procedure: Option<ConcurrencySlot>,
 }
 
 /// Provides descriptions of services and executes procedure calls
@@ -53,288 +170,1061 @@ pub trait ServiceHandler: Send + Sync {
     ///
     /// Any errors while invoking need to be handled by this method.
     ///
-    /// Generally procedure calls are spawned in a background task.
+    /// `payload` is still encoded, procedures decode it into their own
+    /// types with [`RawPayload::decode`].
+    ///
+    /// The dispatcher runs every invocation in its own task, so the procedure
+    /// can be run directly inside of this method. Work spawned into other tasks
+    /// is not covered by procedure timeouts.
     fn invoke_rpc(
         &self,
         service: String,
         procedure: String,
         metadata: RPCMetadata,
         channel: AsyncSender<OutgoingMessage>,
-        payload: serde_json::Value,
+        payload: RawPayload,
         recv: AsyncReceiver<IncomingMessage>,
-    ) -> impl std::future::Future<Output = ()> + Send + Sync;
+    ) -> impl std::future::Future<Output = ()> + Send;
 }
 
-impl<H: ServiceHandler + 'static, C: Codec + 'static> RiverServer<H, C> {
+impl<H: ServiceHandler + 'static> RiverServer<H> {
     /// Creates a new RiverServer with default settings.
     ///
     /// Heartbeats are sent every second, if this needs to be changed
     /// use [`RiverServer::new_with_heartbeat_interval`](Self::new_with_heartbeat_interval).
-    pub fn new(codec: C, handler: H) -> Self {
-        RiverServer {
-            codec,
-            service_description: handler.description(),
-            service_handler: handler,
-            heartbeat_interval: Duration::from_secs(1),
-        }
+    ///
+    /// Any [`Codec`](crate::types::Codec) can be used, as well as an
+    /// `Arc<dyn TransportCodec>` when the codec is picked at runtime.
+    ///
+    /// For more settings, see [`RiverServer::builder`].
+    pub fn new(codec: impl TransportCodec + 'static, handler: H) -> Self {
+        Self::from_codec(Arc::new(codec), handler, Duration::from_secs(1))
     }
 
     /// Creates a new RiverServer with a custom heartbeat interval, to disable heartbeats
     /// set the interval to 0 seconds.
-    pub fn new_with_heartbeat_interval(codec: C, handler: H, interval: Duration) -> Self {
+    pub fn new_with_heartbeat_interval(
+        codec: impl TransportCodec + 'static,
+        handler: H,
+        interval: Duration,
+    ) -> Self {
+        Self::from_codec(Arc::new(codec), handler, interval)
+    }
+
+    fn from_codec(
+        codec: Arc<dyn TransportCodec>,
+        handler: H,
+        heartbeat_interval: Duration,
+    ) -> Self {
         RiverServer {
             codec,
             service_description: handler.description(),
             service_handler: handler,
-            heartbeat_interval: interval,
+            heartbeat_interval,
+            default_timeout: None,
+            service_timeouts: HashMap::new(),
+            procedure_timeouts: HashMap::new(),
+            max_streams_per_session: None,
+            procedure_limits: HashMap::new(),
+            rate_limit: None,
+            codec_negotiation: None,
+            v1_1_compatibility: false,
+            max_frame_size: None,
+            default_max_payload_size: None,
+            procedure_max_payload_sizes: HashMap::new(),
+            server_id: generate_id(),
+            heartbeats_until_dead: None,
+            handshake_timeout: Duration::from_secs(10),
+            session_grace_period: Duration::ZERO,
+            session_buffer_limit: BufferLimit::default(),
+            outgoing_channel_capacity: None,
+            stream_channel_capacity: None,
+            hooks: builder::Hooks::default(),
+            sessions: sessions::OwnedSessions::default(),
+            session_store: Arc::new(MemorySessionStore::new()),
+        }
+    }
+
+    /// Returns whether clients speaking `version` are accepted
+    fn supports_version(&self, version: &ProtocolVersion) -> bool {
+        *version == crate::PROTOCOL_VERSION
+            || (self.v1_1_compatibility && *version == ProtocolVersion::V1_1)
+    }
+
+    /// The id this server puts in the `from` field of every message
+    ///
+    /// Randomly generated unless set with [`RiverServerBuilder::server_id`].
+    /// Unique ids let clients and logs tell instances of a fleet apart.
+    pub fn server_id(&self) -> &str {
+        &self.server_id
+    }
+
+    /// Returns whether a client with `session_id` is connected to this server
+    ///
+    /// Sessions stay owned during the [session grace period](RiverServerBuilder::session_grace_period)
+    /// after their client disconnects. Together with
+    /// [affinity tokens](RiverServerBuilder::affinity_token) this lets proxies
+    /// route reconnecting clients back to the instance that owns their session.
+    pub fn owns_session(&self, session_id: &str) -> bool {
+        self.sessions.contains(session_id)
+    }
+
+    /// The largest frame stream transports should accept
+    pub(crate) fn stream_max_frame_size(&self) -> usize {
+        self.max_frame_size
+            .unwrap_or(crate::transport::stream::DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// The codec used when none was negotiated
+    pub(crate) fn codec(&self) -> &Arc<dyn TransportCodec> {
+        &self.codec
+    }
+
+    /// Returns whether disconnected sessions can be resumed
+    fn resumable(&self) -> bool {
+        !self.session_grace_period.is_zero()
+    }
+
+    /// Finds the state of the session a client handshakes with
+    ///
+    /// Returns `Ok(None)` if the client starts a new session, or the reason
+    /// why the session it expects can't be resumed.
+    async fn resumable_state(
+        &self,
+        client_id: &str,
+        request: &HandshakeRequest,
+    ) -> Result<Option<SessionState>, String> {
+        let expected = &request.expected_session_state;
+        let new_session = expected.next_expected_seq == 0 && expected.next_sent_seq == 0;
+
+        let stored = if self.resumable() {
+            // A connection still serving the session saves it before letting go
+            self.sessions.detach(&request.session_id, client_id).await;
+
+            let session_id = request.session_id.clone();
+
+            with_store(self.session_store.clone(), "load", move |store| {
+                store.load(&session_id)
+            })
+            .await
+            .flatten()
+        } else {
+            None
+        };
+
+        match stored {
+            Some(mut state) if state.client_id == client_id && state.can_resume(expected) => {
+                // Messages the client already received don't have to be sent again
+                state
+                    .unacked
+                    .retain(|message| i64::from(message.seq) >= expected.next_expected_seq);
+
+                Ok(Some(state))
+            }
+            _ if new_session => Ok(None),
+            Some(_) => Err("Session state does not match".to_string()),
+            None => Err("Session not found".to_string()),
         }
     }
 
+    /// Stores `state` so the session can be resumed, logging any error
+    async fn save_session(&self, state: SessionState) {
+        with_store(self.session_store.clone(), "save", move |store| {
+            store.save(&state)
+        })
+        .await;
+    }
+
+    /// Removes a session from the store unless it is still served, logging any error
+    async fn forget_session(
+        store: Arc<dyn SessionStore>,
+        sessions: &sessions::OwnedSessions,
+        session_id: &str,
+    ) {
+        if sessions.contains(session_id) {
+            return;
+        }
+
+        let session_id = session_id.to_string();
+        with_store(store, "remove", move |store| store.remove(&session_id)).await;
+    }
+
+    /// Returns the payload size limit that applies to `service.procedure`, if any
+    fn max_payload_size(&self, service: &str, procedure: &str) -> Option<usize> {
+        self.procedure_max_payload_sizes
+            .get(&(service.to_string(), procedure.to_string()))
+            .copied()
+            .or(self.default_max_payload_size)
+    }
+
+    /// Returns the timeout that applies to `service.procedure`, if any
+    fn procedure_timeout(&self, service: &str, procedure: &str) -> Option<Duration> {
+        self.procedure_timeouts
+            .get(&(service.to_string(), procedure.to_string()))
+            .or_else(|| self.service_timeouts.get(service))
+            .copied()
+            .or(self.default_timeout)
+    }
+
+    /// Binds to `addr` and serves WebSocket clients on any path
+    ///
+    /// This only returns if the server fails.
+    ///
+    /// # Errors
+    /// Returns an error if `addr` can't be bound or serving fails.
+    pub async fn serve(self: Arc<Self>, addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(addr = %listener.local_addr()?, server_id = self.server_id, "River server listening");
+
+        self.serve_listener(listener).await
+    }
+
+    /// Serves WebSocket clients on any path from an already bound `listener`
+    ///
+    /// # Errors
+    /// Returns an error if serving fails.
+    pub async fn serve_listener(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        let app = Router::new().fallback(get(|parts, ws| self.delta(parts, ws)));
+
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
+
+        Ok(())
+    }
+
     /// Used as an [`axum`] route handler
     ///
-    /// See the `test-server` example for how to use this method.
-    #[allow(clippy::unused_async, reason = "Required for use as axum handler")]
-    pub async fn delta(
+    /// See the `test-server` example for how to use this method. The app
+    /// must be served with `into_make_service_with_connect_info::<SocketAddr>()`,
+    /// [`RiverServer::serve`] takes care of this when no other routes are needed.
+    ///
+    /// Requests can be rejected before they are upgraded with
+    /// [`RiverServerBuilder::on_upgrade`].
+    pub async fn delta(self: Arc<Self>, parts: Parts, mut ws: WebSocketUpgrade) -> Response {
+        let Some(&ConnectInfo(addr)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            error!(
+                "Missing connect info, serve the app with `into_make_service_with_connect_info`"
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let query = parts.uri.query().map(str::to_string);
+
+        if let Some(hook) = &self.hooks.upgrade {
+            if let Err(response) = hook(UpgradeRequest { addr, parts }).await {
+                warn!(%addr, status = %response.status(), "Upgrade rejected");
+                return response;
+            }
+        }
+
+        // Oversized messages are rejected before they are buffered
+        if let Some(max) = self.max_frame_size {
+            ws = ws.max_message_size(max).max_frame_size(max);
+        }
+
+        let mut codec = None;
+
+        if let Some(negotiation) = &self.codec_negotiation {
+            ws = ws.protocols(negotiation.offered_subprotocols());
+
+            let subprotocol = ws
+                .selected_protocol()
+                .and_then(|protocol| protocol.to_str().ok());
+            codec = negotiation.select(subprotocol, query.as_deref());
+        }
+
+        ws.on_upgrade(move |socket| {
+            self.serve_connection(WebSocketTransport::new(socket), addr.to_string(), codec)
+        })
+    }
+
+    /// Serves a single client connected over `transport`
+    ///
+    /// Resolves once the client disconnects. `peer` describes the client in
+    /// logs, like its address. Codecs are still sniffed from the handshake if
+    /// [codec negotiation](RiverServerBuilder::codec_negotiation) is enabled.
+    pub async fn serve_transport(self: Arc<Self>, transport: impl Transport, peer: String) {
+        self.serve_connection(transport, peer, None).await;
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn serve_connection(
         self: Arc<Self>,
-        ConnectInfo(addr): ConnectInfo<SocketAddr>,
-        ws: WebSocketUpgrade,
-    ) -> Response {
-        ws.on_upgrade(move |socket| self.on_delta_upgrade(socket, addr))
-    }
-
-    async fn on_delta_upgrade(self: Arc<Self>, mut socket: WebSocket, addr: SocketAddr) {
-        info!(%addr, "New Connection");
-
-        let client_id: String;
-
-        if let Some(Ok(WsMessage::Binary(data))) = socket.recv().await {
-            let data: TransportControlMessage = self.codec.decode_slice(&data).unwrap();
-            if let Control::HandshakeRequest(HandshakeRequest {
-                protocol_version,
-                session_id,
-                expected_session_state: _,
-                metadata: _,
-            }) = &data.payload
-            {
-                debug!(%addr, "Handshake Recieved");
-                client_id = data.header.from.clone();
-                info!(%addr, client_id, "Identified Client");
-
-                let valid;
-                let connection_response;
-
-                if *protocol_version == crate::PROTOCOL_VERSION {
-                    valid = true;
-                    connection_response = TransportControlMessage {
-                        header: Header {
-                            id: generate_id(),
-                            from: "SERVER".to_string(),
-                            to: data.header.from,
-                            seq: 0,
-                            ack: 0,
-                            control_flags: 0,
-                            stream_id: generate_id(),
-                        },
-                        payload: Control::HandshakeResponse(HandshakeResponse {
-                            status: RiverResult::<HandshakeResponseOk, String>::Ok(
-                                HandshakeResponseOk {
-                                    session_id: session_id.clone(),
-                                },
-                            )
-                            .into(),
-                        }),
-                    };
-                } else {
+        mut transport: impl Transport,
+        peer: String,
+        negotiated_codec: Option<Arc<dyn TransportCodec>>,
+    ) {
+        info!(peer, server_id = self.server_id, "New Connection");
+
+        let received = if self.handshake_timeout.is_zero() {
+            transport.recv().await
+        } else if let Ok(received) = time::timeout(self.handshake_timeout, transport.recv()).await {
+            received
+        } else {
+            warn!(peer, "Client did not handshake in time, closing connection");
+            let _ = transport.close(CloseReason::HandshakeTimeout).await;
+            return;
+        };
+
+        // Clients that handshake with a text frame get text frames back
+        let (data, text_frames) = match received {
+            Some(Ok(Frame { data, text })) => (data, text),
+            Some(Err(err)) => {
+                warn!(peer, %err, "Transport error during handshake");
+                return;
+            }
+            None => {
+                debug!(peer, "Connection closed before handshake");
+                return;
+            }
+        };
+
+        if self.max_frame_size.is_some_and(|max| data.len() > max) {
+            warn!(peer, size = data.len(), "Handshake frame too large");
+            let _ = transport.close(CloseReason::FrameTooLarge).await;
+            return;
+        }
+
+        let codec = negotiated_codec
+            .or_else(|| {
+                self.codec_negotiation
+                    .as_ref()
+                    .and_then(|negotiation| negotiation.sniff_handshake(&data))
+            })
+            .unwrap_or_else(|| self.codec.clone());
+
+        if text_frames && !codec.is_text() {
+            warn!(
+                peer,
+                "Text handshake received for a binary codec, closing connection"
+            );
+            let _ = transport.close(CloseReason::UnsupportedFrame).await;
+            return;
+        }
+
+        let message = match codec.decode_control(&data) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!(peer, %err, "Malformed handshake, closing connection");
+                self.reject_malformed_handshake(
+                    &mut transport,
+                    &*codec,
+                    text_frames,
+                    String::new(),
+                    "Malformed handshake",
+                )
+                .await;
+                return;
+            }
+        };
+
+        let Control::HandshakeRequest(request) = &message.payload else {
+            warn!(
+                peer,
+                "First message was not a handshake request, closing connection"
+            );
+            self.reject_malformed_handshake(
+                &mut transport,
+                &*codec,
+                text_frames,
+                message.header.from,
+                "Expected a handshake request",
+            )
+            .await;
+            return;
+        };
+
+        debug!(peer, "Handshake Recieved");
+        let client_id = message.header.from.clone();
+        let session_id = request.session_id.clone();
+        let negotiated_version = request.protocol_version.clone();
+        let mut resumed = None;
+        info!(peer, client_id, "Identified Client");
+
+        let rejection = if !self.supports_version(&request.protocol_version) {
+            warn!(
+                attempted_version = %request.protocol_version,
+                wanted_version = %crate::PROTOCOL_VERSION,
+                client_id,
+                "Client tried to connect with incorrect version, closing connection"
+            );
+
+            Some((
+                HandshakeError::ProtocolVersionMismatch,
+                format!("Expected version {}", crate::PROTOCOL_VERSION),
+            ))
+        } else if let Some(Err(message)) = self.hooks.handshake.as_ref().map(|hook| hook(request)) {
+            warn!(client_id, message, "Handshake rejected, closing connection");
+
+            Some((HandshakeError::RejectedByCustomHandler, message))
+        } else {
+            match self.resumable_state(&client_id, request).await {
+                Ok(state) => {
+                    resumed = state;
+                    None
+                }
+                Err(message) => {
                     warn!(
-                        attempted_version = %protocol_version,
-                        wanted_version = %crate::PROTOCOL_VERSION,
                         client_id,
-                        "Client tried to connect with incorrect version, closing connection"
+                        message, "Session can't be resumed, closing connection"
                     );
-                    valid = false;
-                    connection_response = TransportControlMessage {
-                        header: Header {
-                            id: generate_id(),
-                            from: "SERVER".to_string(),
-                            to: data.header.from,
-                            seq: 0,
-                            ack: 0,
-                            control_flags: 0,
-                            stream_id: generate_id(),
-                        },
-                        payload: Control::HandshakeResponse(HandshakeResponse {
-                            status: RiverResult::<HandshakeResponseOk, HandshakeError>::Err {
-                                message: format!("Expected version {}", crate::PROTOCOL_VERSION),
-                                code: HandshakeError::ProtocolVersionMismatch,
-                            }
-                            .into(),
-                        }),
-                    };
-                }
-
-                socket
-                    .send(WsMessage::Binary(Bytes::from_owner(
-                        self.codec.encode_to_vec(&connection_response).unwrap(),
-                    )))
-                    .await
-                    .unwrap();
 
-                if !valid {
-                    return;
+                    Some((HandshakeError::SessionStateMismatch, message))
                 }
+            }
+        };
 
-                debug!(%client_id, "Handshake Complete");
-            } else {
-                warn!("Handshake req not first message");
-                socket.send(WsMessage::Close(None)).await.unwrap();
+        let status = match rejection {
+            None => RiverResult::Ok(HandshakeResponseOk {
+                session_id: session_id.clone(),
+                affinity_token: self.hooks.affinity.as_ref().and_then(|hook| hook(request)),
+            }),
+            Some((code, message)) => {
+                self.send_handshake_response(
+                    &mut transport,
+                    &*codec,
+                    text_frames,
+                    client_id,
+                    RiverResult::Err { code, message },
+                )
+                .await;
+                let _ = transport.close(CloseReason::Normal).await;
                 return;
             }
-        } else {
+        };
+
+        if !self
+            .send_handshake_response(
+                &mut transport,
+                &*codec,
+                text_frames,
+                client_id.clone(),
+                status,
+            )
+            .await
+        {
             return;
         }
 
-        let span = info_span!("event_loop", client_id, %addr);
+        debug!(%client_id, "Handshake Complete");
+
+        let span = info_span!("event_loop", server_id = self.server_id, client_id, peer);
+        let session = SessionInfo {
+            client_id,
+            session_id,
+            peer,
+            protocol_version: negotiated_version,
+        };
+
+        if let Some(hook) = &self.hooks.connect {
+            hook(&session);
+        }
+
+        let connection = Connection {
+            codec,
+            text_frames,
+            claim: self.sessions.claim(session.session_id.clone()),
+            session: session.clone(),
+            resumed,
+        };
 
-        self.event_loop(socket, client_id, addr, span.clone())
+        if let Err(err) = self
+            .clone()
+            .event_loop(transport, connection)
             .instrument(span)
             .await
-            .unwrap();
+        {
+            warn!(peer = session.peer, %err, "Connection closed with an error");
+        }
+
+        if self.resumable() {
+            Self::forget_session(
+                self.session_store.clone(),
+                &self.sessions,
+                &session.session_id,
+            )
+            .await;
+        }
+
+        if let Some(hook) = &self.hooks.disconnect {
+            hook(&session);
+        }
     }
 
-    async fn heartbeats(
-        sender: AsyncSender<OutgoingMessage>,
-        interval: Duration,
-    ) -> anyhow::Result<()> {
-        let mut interval = time::interval(interval);
-
-        // Best attempt to send every interval
-        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-
-        loop {
-            interval.tick().await;
-
-            debug!("Heartbeat Sent");
-            sender
-                .send(OutgoingMessage {
-                    message: SimpleOutgoingMessage::Control(0b0001, Control::Ack),
-                    stream_id: "heartbeat".to_string(),
-                    close: false,
-                })
-                .await?;
+    /// Sends the response to a handshake, returning whether it was sent
+    async fn send_handshake_response(
+        &self,
+        transport: &mut impl Transport,
+        codec: &dyn TransportCodec,
+        text_frames: bool,
+        client_id: String,
+        status: RiverResult<HandshakeResponseOk, HandshakeError>,
+    ) -> bool {
+        let response = TransportControlMessage {
+            header: Header {
+                id: generate_id(),
+                from: self.server_id.clone(),
+                to: client_id,
+                seq: 0,
+                ack: 0,
+                control_flags: 0,
+                stream_id: generate_id(),
+            },
+            payload: Control::HandshakeResponse(HandshakeResponse {
+                status: status.into(),
+            }),
+        };
+
+        let result = match codec.encode_control(&response) {
+            Ok(data) => {
+                transport
+                    .send(outgoing_frame(data.into(), text_frames))
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = &result {
+            warn!(%err, "Failed to send handshake response");
         }
+
+        result.is_ok()
+    }
+
+    /// Rejects a handshake that isn't a valid handshake request and closes the connection
+    async fn reject_malformed_handshake(
+        &self,
+        transport: &mut impl Transport,
+        codec: &dyn TransportCodec,
+        text_frames: bool,
+        client_id: String,
+        message: &str,
+    ) {
+        let status = RiverResult::Err {
+            code: HandshakeError::MalformedHandshake,
+            message: message.to_string(),
+        };
+
+        self.send_handshake_response(transport, codec, text_frames, client_id, status)
+            .await;
+        let _ = transport.close(CloseReason::ProtocolError).await;
     }
 
-    async fn close_handler(streams: &mut HashMap<String, StreamInfo>, reason: &str) -> Result<()> {
+    /// Takes a session and procedure slot for a new invocation of `service.procedure`
+    ///
+    /// Returns `None` if either limit has been reached.
+    fn acquire_slots(
+        &self,
+        session_limit: &ConcurrencyLimit,
+        service: &str,
+        procedure: &str,
+    ) -> Option<ProcedureSlots> {
+        let session = session_limit.acquire()?;
+        let procedure = match self
+            .procedure_limits
+            .get(&(service.to_string(), procedure.to_string()))
+        {
+            Some(limit) => Some(limit.acquire()?),
+            None => None,
+        };
+
+        Some(ProcedureSlots {
+            _session: session,
+            _procedure: procedure,
+        })
+    }
+
+    /// Runs a procedure in its own task, cancelling it if it exceeds its timeout
+    #[allow(clippy::too_many_arguments)]
+    fn spawn_procedure(
+        self: &Arc<Self>,
+        service: String,
+        procedure: String,
+        metadata: RPCMetadata,
+        channel: AsyncSender<OutgoingMessage>,
+        payload: RawPayload,
+        recv: AsyncReceiver<IncomingMessage>,
+        slots: ProcedureSlots,
+    ) -> AbortHandle {
+        let server = self.clone();
+        let timeout = self.procedure_timeout(&service, &procedure);
+
+        tokio::spawn(
+            async move {
+                let invocation = server.service_handler.invoke_rpc(
+                    service.clone(),
+                    procedure.clone(),
+                    metadata.clone(),
+                    channel.clone(),
+                    payload,
+                    recv,
+                );
+
+                match timeout {
+                    None => invocation.await,
+                    Some(timeout) => {
+                        if time::timeout(timeout, invocation).await.is_err() {
+                            warn!(
+                                service,
+                                procedure,
+                                stream_id = metadata.stream_id,
+                                ?timeout,
+                                "Procedure timed out"
+                            );
+
+                            let message = procedure_error(
+                                &metadata,
+                                ProcedureError::Cancel,
+                                format!("{service}.{procedure} timed out after {timeout:?}"),
+                            );
+
+                            if channel.send(message).await.is_err() {
+                                debug!("Connection closed before timeout could be reported");
+                            }
+                        }
+                    }
+                }
+
+                // Slots are held until the procedure finishes or is cancelled
+                drop(slots);
+            }
+            .in_current_span(),
+        )
+        .abort_handle()
+    }
+
+    fn close_handler(streams: &mut HashMap<String, StreamInfo>, reason: &str) {
         for (key, entry) in streams.drain() {
             debug!(stream_id = key, "Closing stream due to {reason}");
-            entry.messenger.send(IncomingMessage::ForceClose).await?;
+            entry.force_close();
         }
+    }
 
-        Ok(())
+    /// Sets up the procedures of a new session, with none running yet
+    fn session_procedures(&self) -> sessions::SessionProcedures {
+        let (send, recv) = channel(self.outgoing_channel_capacity);
+        let (forward, outgoing) = mpsc::channel(1);
+
+        // Receiving from kanal isn't cancel safe, so messages are moved to a
+        // channel the event loop can select on and hand to a resumed session
+        tokio::spawn(
+            async move {
+                while let Ok(message) = recv.recv().await {
+                    if forward.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        sessions::SessionProcedures {
+            streams: HashMap::new(),
+            send,
+            outgoing,
+            limit: ConcurrencyLimit::new(self.max_streams_per_session),
+        }
+    }
+
+    /// Closes the procedures of a client that went away, once the session grace period ends
+    ///
+    /// The session stays owned by this server until its procedures are closed.
+    /// The session can be resumed until then, from the `state` it had when the
+    /// client left, and takes over the procedures if it resumes on this server.
+    async fn expire_session(
+        &self,
+        mut procedures: sessions::SessionProcedures,
+        claim: sessions::SessionClaim,
+        state: SessionState,
+        reason: &'static str,
+    ) {
+        if !self.resumable() {
+            Self::close_handler(&mut procedures.streams, reason);
+            drop(claim);
+            return;
+        }
+
+        debug!(grace_period = ?self.session_grace_period, "Keeping disconnected session");
+        self.save_session(state.clone()).await;
+
+        let (generation, replaced) = self.sessions.park(claim, procedures);
+        if let Some(mut replaced) = replaced {
+            Self::close_handler(&mut replaced.streams, "session replaced");
+        }
+
+        let grace_period = self.session_grace_period;
+        let store = self.session_store.clone();
+        let sessions = self.sessions.clone();
+
+        tokio::spawn(
+            async move {
+                time::sleep(grace_period).await;
+
+                if let Some(mut procedures) = sessions.expire(&state.session_id, generation) {
+                    Self::close_handler(&mut procedures.streams, reason);
+                    drop(procedures);
+                    Self::forget_session(store, &sessions, &state.session_id).await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Encodes a message for the client and records it as sent
+    fn encode_outgoing(
+        &self,
+        codec: &dyn TransportCodec,
+        progress: &mut sessions::SessionProgress,
+        client_id: &str,
+        v1_1: bool,
+        ipc: OutgoingMessage,
+    ) -> Result<Bytes> {
+        let mut header = Header {
+            stream_id: ipc.stream_id,
+            id: generate_id(),
+            to: client_id.to_string(),
+            from: self.server_id.clone(),
+            seq: progress.next_seq(),
+            ack: progress.ack(),
+            control_flags: -1,
+        };
+        let seq = header.seq;
+
+        let translate_flags = |control_flags| {
+            if v1_1 {
+                compat::control_flags_to_v1_1(control_flags)
+            } else {
+                control_flags
+            }
+        };
+
+        let data = match ipc.message {
+            SimpleOutgoingMessage::Control(control_flags, msg) => {
+                header.control_flags = translate_flags(control_flags);
+                codec.encode_control(&TransportControlMessage {
+                    header,
+                    payload: msg,
+                })?
+            }
+            SimpleOutgoingMessage::Request(control_flags, msg) => {
+                header.control_flags = translate_flags(control_flags);
+                codec.encode_request(&TransportRequestMessage { header, inner: msg })?
+            }
+        };
+
+        let data = Bytes::from(data);
+        progress.sent(seq, &data);
+
+        Ok(data)
     }
 
     #[allow(clippy::too_many_lines)]
     async fn event_loop(
         self: Arc<Self>,
-        mut socket: WebSocket,
-        client_id: String,
-        addr: SocketAddr,
-        span: Span,
+        mut transport: impl Transport,
+        connection: Connection,
     ) -> Result<()> {
-        let _ = span;
-        let _ = addr;
-        let mut streams: HashMap<String, StreamInfo> = HashMap::new();
-        let mut seq = 0;
+        let Connection {
+            codec,
+            text_frames,
+            claim,
+            session:
+                SessionInfo {
+                    client_id,
+                    session_id,
+                    protocol_version,
+                    ..
+                },
+            resumed,
+        } = connection;
+        let v1_1 = protocol_version == ProtocolVersion::V1_1;
+        let mut rate_limiter = self
+            .rate_limit
+            .map(|rate_limit| (rate_limit.action(), rate_limit.limiter()));
+
+        let (_attached, mut detach_requests) =
+            self.sessions.attach(session_id.clone(), client_id.clone());
+        let mut detached = None;
 
-        let (send, recv) = kanal::unbounded_async();
+        let parked = self.sessions.unpark(&session_id);
+        let resuming = resumed.is_some();
+        let mut progress = match resumed {
+            Some(state) => {
+                info!(unacked = state.unacked.len(), "Resuming session");
+                sessions::SessionProgress::resume(state, self.session_buffer_limit)
+            }
+            None => sessions::SessionProgress::new(self.resumable(), self.session_buffer_limit),
+        };
 
-        if !self.heartbeat_interval.is_zero() {
-            let send = send.clone();
-            let heartbeat_interval = self.heartbeat_interval;
+        // Procedures still running on this server carry on with the resumed session
+        let procedures = match parked {
+            Some(procedures) if resuming => {
+                info!(
+                    streams = procedures.streams.len(),
+                    "Taking over procedures of resumed session"
+                );
+                procedures
+            }
+            Some(mut procedures) => {
+                Self::close_handler(&mut procedures.streams, "new session");
+                self.session_procedures()
+            }
+            None => self.session_procedures(),
+        };
+        let sessions::SessionProcedures {
+            mut streams,
+            send,
+            mut outgoing,
+            limit: session_limit,
+        } = procedures;
 
-            tokio::spawn(async move {
-                Self::heartbeats(send, heartbeat_interval)
-                    .instrument(span)
-                    .await
-            });
-        }
+        // Messages the client missed are sent again before anything else
+        let mut resend: VecDeque<Bytes> = progress.unacked().cloned().collect();
+
+        // Resumable sessions are saved regularly in case the server goes away
+        let save_every = (self.resumable() && !self.heartbeat_interval.is_zero())
+            .then_some(self.heartbeat_interval);
+        let mut next_save = deadline(Instant::now(), save_every.unwrap_or_default());
+
+        // Clients answer heartbeats, so a long silence means the client is gone
+        let dead_after = self
+            .heartbeats_until_dead
+            .map(|heartbeats| self.heartbeat_interval.saturating_mul(heartbeats));
+        let mut last_received = Instant::now();
+
+        // Delayed sessions aren't read from until they are back within their rate limit
+        let mut paused_until: Option<Instant> = None;
+
+        // Messages from the event loop itself, which must never wait on the
+        // outgoing channel as it is the only task draining it
+        let mut replies = VecDeque::new();
+
+        let heartbeat_every =
+            (!self.heartbeat_interval.is_zero()).then_some(self.heartbeat_interval);
+        let mut next_heartbeat = Instant::now();
+
+        // Clients that went away keep their procedures until the session expires
+        let reason = 'session: loop {
+            while let Some(data) = resend.pop_front() {
+                if let Err(err) = transport.send(outgoing_frame(data, text_frames)).await {
+                    error!("Transport error: {err}");
+                    break 'session "transport error";
+                }
+            }
+
+            while let Some(ipc) = replies.pop_front() {
+                // Messages are kept as unacknowledged even if sending them fails
+                let data = self.encode_outgoing(&*codec, &mut progress, &client_id, v1_1, ipc)?;
+
+                if let Err(err) = transport.send(outgoing_frame(data, text_frames)).await {
+                    error!("Transport error: {err}");
+                    break 'session "transport error";
+                }
+            }
+
+            if progress.buffer_full() {
+                warn!("Client is not acknowledging messages, ending session");
+
+                transport.close(CloseReason::SessionBufferFull).await.ok();
+
+                Self::close_handler(&mut streams, "session buffer full");
+                drop(claim);
+                Self::forget_session(self.session_store.clone(), &self.sessions, &session_id).await;
+
+                return Ok(());
+            }
 
-        loop {
             tokio::select! {
-                ws_msg = socket.recv() => {
-                    let msg = match ws_msg {
-                        None => break,
-                        Some(msg) => msg,
+                frame = transport.recv(), if paused_until.is_none() => {
+                    let frame = match frame {
+                        None => {
+                            info!("Client Disconnected");
+                            break 'session "disconnect";
+                        },
+                        Some(Ok(frame)) => frame,
+                        Some(Err(err)) => {
+                            error!("Transport error: {err}");
+                            break 'session "transport error";
+                        },
                     };
 
-                    let msg = match msg {
-                        Ok(msg) => msg,
-                        Err(err) => {
-                            let error_message = err.to_string();
+                    last_received = Instant::now();
 
-                            if error_message.contains("Connection reset without closing handshake") {
-                                warn!("Client connection reset without closing handshake");
+                    // Text frames are handled the same way as binary ones for text codecs
+                    match frame {
+                        Frame { data, text } if !text || codec.is_text() => {
+                            if self.max_frame_size.is_some_and(|max| data.len() > max) {
+                                warn!(size = data.len(), "Frame too large, disconnecting client");
 
-                                Self::close_handler(&mut streams, "unexpected disconnect").await?;
+                                transport.close(CloseReason::FrameTooLarge).await?;
+
+                                Self::close_handler(&mut streams, "oversized frame");
 
                                 return Ok(());
-                            } else {
-                                error!("WebSocket error: {}", error_message);
+                            }
 
-                                Self::close_handler(&mut streams, "websocket error").await?;
+                            let data_len = data.len();
+                            let mut over_rate_limit = false;
 
-                                return Ok(());
+                            if let Some((action, limiter)) = &mut rate_limiter {
+                                if let Some(wait) = limiter.record(data.len()) {
+                                    match action {
+                                        RateLimitAction::Delay => {
+                                            debug!(?wait, "Rate limit exceeded, delaying session");
+                                            paused_until = Some(deadline(Instant::now(), wait));
+                                        }
+                                        RateLimitAction::RejectStreams => over_rate_limit = true,
+                                        RateLimitAction::Disconnect => {
+                                            warn!("Rate limit exceeded, disconnecting client");
+
+                                            transport.close(CloseReason::RateLimited).await?;
+
+                                            Self::close_handler(&mut streams, "rate limit");
+
+                                            return Ok(());
+                                        }
+                                    }
+                                }
+                            }
+
+                            let mut frame = match codec.decode_frame(&data) {
+                                Ok(frame) => frame,
+                                Err(err) => {
+                                    warn!(%err, "Ignoring malformed message");
+                                    continue;
+                                }
+                            };
+
+                            if !progress.receive(frame.header.seq, frame.header.ack) {
+                                debug!(seq = frame.header.seq, "Skipping message handled before the session resumed");
+                                continue;
                             }
-                        },
-                    };
 
-                    match msg {
-                        WsMessage::Binary(data) => {
-                            let header_id: HeaderID = self.codec.decode_slice(&data).unwrap();
+                            // Payloads borrowed from the frame keep sharing its buffer
+                            let payload = RawPayload::new(
+                                match frame.payload {
+                                    Cow::Borrowed(payload) => data.slice_ref(payload),
+                                    Cow::Owned(payload) => Bytes::from(payload),
+                                },
+                                codec.clone(),
+                            );
 
-                            let stream_id = header_id.stream_id.clone();
+                            if v1_1 {
+                                frame.header.control_flags = compat::control_flags_from_v1_1(frame.header.control_flags);
+                            }
+
+                            let stream_id = frame.header.stream_id;
+
+                            if frame.header.control_flags & 0b0100 == 0b0100 {
+                                if let Some(stream_info) = streams.remove(&stream_id) {
+                                    debug!(stream_id, "Stream cancelled by client");
+                                    stream_info.force_close();
+                                }
+
+                                continue;
+                            }
 
                             // TODO: confirm that procedure sent has right type
                             if let Some(stream_info) = streams.get(&stream_id) {
-                                let data: TransportRequestMessage = self.codec.decode_slice(&data)?;
-                                if data.header.control_flags & 0b1000 == 0b1000 {
-                                    stream_info.messenger.send(IncomingMessage::Close).await?;
-                                } else if let RequestInner::Request { payload } = data.inner {
-                                    stream_info.messenger.send(IncomingMessage::Request(payload)).await?;
+                                if stream_info.max_payload_size.is_some_and(|max| data.len() > max) {
+                                    warn!(stream_id, size = data.len(), "Payload too large, cancelling stream");
+
+                                    if let Some(stream_info) = streams.remove(&stream_id) {
+                                        stream_info.force_close();
+                                    }
+
+                                    let metadata = RPCMetadata { stream_id, client_id: client_id.clone() };
+                                    replies.push_back(procedure_error(&metadata, ProcedureError::InvalidRequest, "Payload too large"));
+
+                                    continue;
+                                }
+
+                                let message = if frame.header.control_flags & 0b1000 == 0b1000 {
+                                    IncomingMessage::Close
+                                } else if frame.procedure_name.is_none() {
+                                    IncomingMessage::Request(payload)
                                 } else {
                                     error!("Existing stream but init message?");
-                                }
-                            } else if header_id.procedure_name.is_some() && header_id.service_name.is_some() {
-                                let data: TransportRequestMessage = self.codec.decode_slice(&data)?;
+                                    continue;
+                                };
+
+                                match stream_info.messenger.try_send(message) {
+                                    Ok(true) => {}
+                                    Ok(false) => {
+                                        warn!(stream_id, "Procedure is not reading its messages, cancelling stream");
 
-                                if let RequestInner::Init { payload, service_name, procedure_name } = data.inner {
-                                    let (stream_send, stream_recv) = kanal::unbounded_async();
+                                        if let Some(stream_info) = streams.remove(&stream_id) {
+                                            stream_info.task.abort();
+                                        }
 
-                                    // Only add stream if it is opened and not immediately closed
-                                    if data.header.control_flags & 0b01010 == 0b00010 {
-                                        streams.insert(header_id.stream_id, StreamInfo {
-                                            messenger: stream_send
-                                        });
+                                        let metadata = RPCMetadata { stream_id, client_id: client_id.clone() };
+                                        replies.push_back(procedure_error(&metadata, ProcedureError::Cancel, "Procedure is not keeping up with its messages"));
                                     }
+                                    // The procedure may finish before its stream is removed
+                                    Err(_) => debug!(stream_id, "Dropping message for finished procedure"),
+                                }
+                            } else if let (Some(service_name), Some(procedure_name)) = (frame.service_name, frame.procedure_name) {
+                                let metadata = RPCMetadata { stream_id, client_id: client_id.clone() };
 
-                                    let metadata = RPCMetadata { stream_id, client_id: client_id.clone() };
+                                if let Some(procedures) = self.service_description.get(&service_name) {
+                                    let max_payload_size = self.max_payload_size(&service_name, &procedure_name);
 
-                                    if let Some(procedures) = self.service_description.get(&service_name) {
-                                        if procedures.contains(&procedure_name) {
-                                            self.service_handler.invoke_rpc(service_name, procedure_name, metadata, send.clone(), payload, stream_recv).await;
-                                        } else {
-                                            warn!(service = service_name, procedure = procedure_name, "Unknown Procedure");
+                                    if !procedures.contains(&procedure_name) {
+                                        warn!(service = service_name, procedure = procedure_name, "Unknown Procedure");
+
+                                        replies.push_back(procedure_error(
+                                            &metadata,
+                                            ProcedureError::InvalidRequest,
+                                            format!("Unknown procedure {service_name}.{procedure_name}"),
+                                        ));
+                                    } else if max_payload_size.is_some_and(|max| data_len > max) {
+                                        warn!(service = service_name, procedure = procedure_name, size = data_len, "Payload too large, rejecting procedure");
+
+                                        replies.push_back(procedure_error(&metadata, ProcedureError::InvalidRequest, "Payload too large"));
+                                    } else if over_rate_limit {
+                                        warn!(service = service_name, procedure = procedure_name, "Rate limit exceeded, rejecting procedure");
+
+                                        replies.push_back(procedure_error(&metadata, ProcedureError::Cancel, "Rate limit exceeded"));
+                                    } else if let Some(slots) = self.acquire_slots(&session_limit, &service_name, &procedure_name) {
+                                        let (stream_send, stream_recv) = channel(self.stream_channel_capacity);
+                                        let stream_id = metadata.stream_id.clone();
+                                        let task = self.spawn_procedure(service_name, procedure_name, metadata, send.clone(), payload, stream_recv, slots);
+
+                                        // Only add stream if it is opened and not immediately closed
+                                        if frame.header.control_flags & 0b01010 == 0b00010 {
+                                            streams.insert(stream_id, StreamInfo {
+                                                messenger: stream_send,
+                                                max_payload_size,
+                                                task,
+                                            });
                                         }
                                     } else {
-                                        warn!(service = service_name, "Unknown Service");
+                                        warn!(service = service_name, procedure = procedure_name, "Too many concurrent streams, rejecting procedure");
+
+                                        replies.push_back(procedure_error(
+                                            &metadata,
+                                            ProcedureError::Cancel,
+                                            format!("Too many concurrent streams for {service_name}.{procedure_name}"),
+                                        ));
                                     }
                                 } else {
-                                    error!("Non-existent stream but non-init message?");
+                                    warn!(service = service_name, "Unknown Service");
+
+                                    replies.push_back(procedure_error(
+                                        &metadata,
+                                        ProcedureError::InvalidRequest,
+                                        format!("Unknown service {service_name}"),
+                                    ));
                                 }
                             } else {
-                                let data: TransportControlMessage = self.codec.decode_slice(&data)?;
+                                let Ok(control) = payload.decode::<Control>() else {
+                                    debug!(stream_id, "Ignoring message for unknown stream");
+                                    continue;
+                                };
 
-                                match data.payload {
+                                match control {
                                     Control::Ack => {
                                         debug!("Heartbeat Received");
                                     }
@@ -347,66 +1237,141 @@ impl<H: ServiceHandler + 'static, C: Codec + 'static> RiverServer<H, C> {
                                 }
                             }
                         },
-                        WsMessage::Close(_) => {
-                            info!("Client Disconnected");
-
-                            Self::close_handler(&mut streams, "disconnect").await?;
-
-                            break;
-                        },
-                        _ => {
-                            warn!(?msg, "Unknown message!");
+                        Frame { .. } => {
+                            warn!("Ignoring text frame for a binary codec");
                         }
                     }
                 }
-                ipc = recv.recv() => {
-                    let ipc = ipc?;
+                () = time::sleep_until(deadline(last_received, dead_after.unwrap_or_default())), if dead_after.is_some() => {
+                    warn!("Client missed too many heartbeats, disconnecting");
+
+                    transport.close(CloseReason::Unresponsive).await.ok();
+                    break 'session "missed heartbeats";
+                }
+                () = time::sleep_until(paused_until.unwrap_or_else(Instant::now)), if paused_until.is_some() => {
+                    debug!("Rate limit recovered, reading from session again");
+                    paused_until = None;
+                    // The client wasn't silent, the session just wasn't listening
+                    last_received = Instant::now();
+                }
+                () = time::sleep_until(next_save), if save_every.is_some() => {
+                    self.save_session(progress.state(&session_id, &client_id)).await;
+                    next_save = deadline(next_save, save_every.unwrap_or_default());
+                }
+                () = time::sleep_until(next_heartbeat), if heartbeat_every.is_some() => {
+                    debug!("Heartbeat Sent");
+                    replies.push_back(OutgoingMessage {
+                        message: SimpleOutgoingMessage::Control(0b0001, Control::Ack),
+                        stream_id: "heartbeat".to_string(),
+                        close: false,
+                    });
+
+                    // Best attempt to send every interval
+                    next_heartbeat = deadline(Instant::now(), heartbeat_every.unwrap_or_default());
+                }
+                Some(request) = detach_requests.recv() => {
+                    info!("Session resumed on another connection, closing this one");
+
+                    transport.close(CloseReason::Normal).await.ok();
+                    detached = Some(request);
+
+                    break 'session "session resumed elsewhere";
+                }
+                Some(ipc) = outgoing.recv() => {
 
                     if ipc.close {
                         debug!(stream_id = ipc.stream_id, "Stream Closed");
                         streams.remove(&ipc.stream_id);
                     }
 
-                    let mut header = Header {
-                        stream_id: ipc.stream_id,
-                        id: generate_id(),
-                        to: client_id.clone(),
-                        from: "SERVER".to_string(),
-                        seq,
-                        // TODO: deal with ack correctly
-                        ack: 1,
+                    let data = self.encode_outgoing(&*codec, &mut progress, &client_id, v1_1, ipc)?;
 
-                        control_flags: -1,
-                    };
+                    if let Err(err) = transport.send(outgoing_frame(data, text_frames)).await {
+                        error!("Transport error: {err}");
+                        break 'session "transport error";
+                    }
+                }
+            }
+        };
 
-                    seq += 1;
+        let procedures = sessions::SessionProcedures {
+            streams,
+            send,
+            outgoing,
+            limit: session_limit,
+        };
+        self.expire_session(
+            procedures,
+            claim,
+            progress.state(&session_id, &client_id),
+            reason,
+        )
+        .await;
 
-                    let data = match ipc.message {
-                        SimpleOutgoingMessage::Control(control_flags, msg) => {
-                            header.control_flags = control_flags;
-                            self.codec.encode_to_vec(&TransportControlMessage {
-                                header,
-                                payload: msg,
-                            })?
-                        },
-                        SimpleOutgoingMessage::Request(control_flags, msg) => {
-                            header.control_flags = control_flags;
-                            self.codec.encode_to_vec(&TransportRequestMessage {
-                                header,
-                                inner: msg,
-                            })?
-                        },
-                    };
+        if let Some(request) = detached {
+            request.send(()).ok();
+        }
 
-                    let to_send = WsMessage::Binary(Bytes::from_owner(
-                        data,
-                    ));
+        Ok(())
+    }
+}
 
-                    socket.send(to_send).await?;
-                }
-            }
+/// Runs `call` with `store` on the blocking thread pool, logging any error
+async fn with_store<T: Send + 'static>(
+    store: Arc<dyn SessionStore>,
+    action: &'static str,
+    call: impl FnOnce(&dyn SessionStore) -> Result<T> + Send + 'static,
+) -> Option<T> {
+    match tokio::task::spawn_blocking(move || call(&*store)).await {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(err)) => {
+            warn!(%err, "Failed to {action} session");
+            None
+        }
+        Err(err) => {
+            warn!(%err, "Session store panicked during {action}");
+            None
         }
+    }
+}
 
-        Ok(())
+/// An instant that is never reached in practice
+fn far_future() -> Instant {
+    Instant::now() + Duration::from_secs(60 * 60 * 24 * 365)
+}
+
+/// The instant `after` has passed since `from`, or one never reached if it is too far away
+fn deadline(from: Instant, after: Duration) -> Instant {
+    from.checked_add(after).unwrap_or_else(far_future)
+}
+
+/// Builds a message that cancels the stream described by `metadata`
+fn procedure_error(
+    metadata: &RPCMetadata,
+    code: ProcedureError,
+    message: impl Into<String>,
+) -> OutgoingMessage {
+    payload_to_msg(
+        ProcedureRes::Response(error_payload(&code, message)),
+        metadata,
+        true,
+        true,
+    )
+}
+
+/// Creates a channel holding at most `capacity` messages, or any amount if `None`
+fn channel<T>(capacity: Option<usize>) -> (AsyncSender<T>, AsyncReceiver<T>) {
+    match capacity {
+        Some(capacity) => kanal::bounded_async(capacity),
+        None => kanal::unbounded_async(),
+    }
+}
+
+/// Wraps an encoded message in a frame, using text frames when requested
+fn outgoing_frame(data: Bytes, text: bool) -> Frame {
+    if text {
+        Frame::text(data)
+    } else {
+        Frame::binary(data)
     }
 }
@@ -0,0 +1,557 @@
+//! Builder for [`RiverServer`]
+
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use anyhow::{Result, bail};
+use axum::response::Response;
+
+use super::{RiverServer, ServiceHandler, SessionInfo, UpgradeRequest};
+use crate::{
+    codecs::CodecNegotiation,
+    limits::{ConcurrencyLimit, RateLimit},
+    sessions::{BufferLimit, SessionStore},
+    types::{HandshakeRequest, TransportCodec},
+};
+
+/// Decides whether a WebSocket upgrade is accepted, see [`RiverServerBuilder::on_upgrade`]
+pub type UpgradeHook = dyn Fn(UpgradeRequest) -> Pin<Box<dyn Future<Output = Result<(), Response>> + Send>>
+    + Send
+    + Sync;
+
+/// Decides whether a handshake is accepted, see [`RiverServerBuilder::on_handshake`]
+pub type HandshakeHook = dyn Fn(&HandshakeRequest) -> Result<(), String> + Send + Sync;
+
+/// Creates the affinity token of an accepted handshake, see [`RiverServerBuilder::affinity_token`]
+pub type AffinityHook = dyn Fn(&HandshakeRequest) -> Option<String> + Send + Sync;
+
+/// Called with a session as it connects or disconnects
+pub type SessionHook = dyn Fn(&SessionInfo) + Send + Sync;
+
+/// Hooks run by the dispatcher during a connection's lifetime
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub(crate) upgrade: Option<Box<UpgradeHook>>,
+    pub(crate) handshake: Option<Box<HandshakeHook>>,
+    pub(crate) affinity: Option<Box<AffinityHook>>,
+    pub(crate) connect: Option<Box<SessionHook>>,
+    pub(crate) disconnect: Option<Box<SessionHook>>,
+}
+
+/// Configures and validates a [`RiverServer`]
+///
+/// Created with [`RiverServer::builder`]. Only the codec and handler are
+/// required, everything else has the same defaults as [`RiverServer::new`].
+pub struct RiverServerBuilder<H: ServiceHandler + 'static> {
+    codec: Option<Arc<dyn TransportCodec>>,
+    handler: Option<H>,
+    server_id: Option<String>,
+    heartbeat_interval: Duration,
+    heartbeats_until_dead: Option<u32>,
+    handshake_timeout: Duration,
+    session_grace_period: Duration,
+    session_store: Option<Arc<dyn SessionStore>>,
+    session_buffer_limit: BufferLimit,
+    max_frame_size: Option<usize>,
+    max_payload_size: Option<usize>,
+    procedure_max_payload_sizes: HashMap<(String, String), usize>,
+    default_timeout: Option<Duration>,
+    service_timeouts: HashMap<String, Duration>,
+    procedure_timeouts: HashMap<(String, String), Duration>,
+    max_streams_per_session: Option<usize>,
+    procedure_concurrency_limits: HashMap<(String, String), usize>,
+    rate_limit: Option<RateLimit>,
+    codec_negotiation: Option<CodecNegotiation>,
+    v1_1_compatibility: bool,
+    outgoing_channel_capacity: Option<usize>,
+    stream_channel_capacity: Option<usize>,
+    hooks: Hooks,
+}
+
+impl<H: ServiceHandler + 'static> RiverServer<H> {
+    /// Creates a [`RiverServerBuilder`]
+    pub fn builder() -> RiverServerBuilder<H> {
+        RiverServerBuilder {
+            codec: None,
+            handler: None,
+            server_id: None,
+            heartbeat_interval: Duration::from_secs(1),
+            heartbeats_until_dead: None,
+            handshake_timeout: Duration::from_secs(10),
+            session_grace_period: Duration::ZERO,
+            session_store: None,
+            session_buffer_limit: BufferLimit::default(),
+            max_frame_size: None,
+            max_payload_size: None,
+            procedure_max_payload_sizes: HashMap::new(),
+            default_timeout: None,
+            service_timeouts: HashMap::new(),
+            procedure_timeouts: HashMap::new(),
+            max_streams_per_session: None,
+            procedure_concurrency_limits: HashMap::new(),
+            rate_limit: None,
+            codec_negotiation: None,
+            v1_1_compatibility: false,
+            outgoing_channel_capacity: None,
+            stream_channel_capacity: None,
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+impl<H: ServiceHandler + 'static> RiverServerBuilder<H> {
+    /// Sets the codec used for connections that don't negotiate one
+    #[must_use]
+    pub fn codec(mut self, codec: impl TransportCodec + 'static) -> Self {
+        self.codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// Sets the handler that procedures are dispatched to
+    #[must_use]
+    pub fn handler(mut self, handler: H) -> Self {
+        self.handler = Some(handler);
+        self
+    }
+
+    /// Sets the id the server puts in the `from` field of every message
+    ///
+    /// Defaults to a random id, see [`RiverServer::server_id`].
+    #[must_use]
+    pub fn server_id(mut self, id: impl Into<String>) -> Self {
+        self.server_id = Some(id.into());
+        self
+    }
+
+    /// Sets how often heartbeats are sent, a zero interval disables them
+    #[must_use]
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Disconnects clients that send nothing for `heartbeats` heartbeat intervals
+    ///
+    /// Clients answer every heartbeat, so a silent client is assumed to be
+    /// gone. By default clients are never disconnected for being silent.
+    #[must_use]
+    pub fn heartbeats_until_dead(mut self, heartbeats: u32) -> Self {
+        self.heartbeats_until_dead = Some(heartbeats);
+        self
+    }
+
+    /// Sets how long clients have to send their handshake after connecting
+    ///
+    /// Clients that take longer are disconnected, so idle connections can't
+    /// tie up the server. Defaults to 10 seconds, a zero timeout disables it.
+    #[must_use]
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a disconnected session can be resumed
+    ///
+    /// The session's procedures keep running, and are taken over if the
+    /// client resumes on this server or cancelled once the grace period ends.
+    /// By default sessions can't be resumed, and their procedures are
+    /// cancelled as soon as the client disconnects. See the
+    /// [`sessions`](crate::sessions) module for how sessions are resumed.
+    #[must_use]
+    pub fn session_grace_period(mut self, grace_period: Duration) -> Self {
+        self.session_grace_period = grace_period;
+        self
+    }
+
+    /// Sets where resumable sessions are stored
+    ///
+    /// Defaults to a [`MemorySessionStore`](crate::sessions::MemorySessionStore).
+    /// Only used if a [session grace period](Self::session_grace_period) is set.
+    #[must_use]
+    pub fn session_store(mut self, store: impl SessionStore + 'static) -> Self {
+        self.session_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Limits how many unacknowledged messages a resumable session may buffer
+    ///
+    /// A session exceeding either limit is ended, as its client can't keep
+    /// up. Defaults to [`BufferLimit::default`]. Only used if a
+    /// [session grace period](Self::session_grace_period) is set.
+    #[must_use]
+    pub fn session_buffer_limit(mut self, messages: usize, bytes: usize) -> Self {
+        self.session_buffer_limit = BufferLimit { messages, bytes };
+        self
+    }
+
+    /// Limits the size of frames clients may send
+    ///
+    /// Frames are checked before they are decoded, clients sending a larger
+    /// frame are disconnected. By default frames are not limited, except on
+    /// stream transports which reject frames over
+    /// [`DEFAULT_MAX_FRAME_SIZE`](crate::transport::stream::DEFAULT_MAX_FRAME_SIZE)
+    /// before buffering them.
+    #[must_use]
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = Some(bytes);
+        self
+    }
+
+    /// Limits the size of messages sent to any procedure
+    ///
+    /// Sizes are measured on the encoded message before its payload is
+    /// decoded, so they include the message header. Procedures receiving a
+    /// larger message are cancelled with an
+    /// [`INVALID_REQUEST`](crate::types::ProcedureError::InvalidRequest) error.
+    /// This can be overridden with [`procedure_max_payload_size`](Self::procedure_max_payload_size).
+    #[must_use]
+    pub fn max_payload_size(mut self, bytes: usize) -> Self {
+        self.max_payload_size = Some(bytes);
+        self
+    }
+
+    /// Limits the size of messages sent to `service.procedure`, overriding
+    /// the [default limit](Self::max_payload_size)
+    #[must_use]
+    pub fn procedure_max_payload_size(
+        mut self,
+        service: impl Into<String>,
+        procedure: impl Into<String>,
+        bytes: usize,
+    ) -> Self {
+        self.procedure_max_payload_sizes
+            .insert((service.into(), procedure.into()), bytes);
+        self
+    }
+
+    /// Sets how long any procedure may run before it is cancelled
+    ///
+    /// When a procedure times out its task is aborted and the client receives
+    /// a [`CANCEL`](crate::types::ProcedureError::Cancel) error. By default
+    /// procedures may run forever. This can be overridden with
+    /// [`service_timeout`](Self::service_timeout) and
+    /// [`procedure_timeout`](Self::procedure_timeout).
+    #[must_use]
+    pub fn default_timeout(mut self, timeout: Duration) -> Self {
+        self.default_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long procedures of `service` may run before they are
+    /// cancelled, overriding the [default timeout](Self::default_timeout)
+    #[must_use]
+    pub fn service_timeout(mut self, service: impl Into<String>, timeout: Duration) -> Self {
+        self.service_timeouts.insert(service.into(), timeout);
+        self
+    }
+
+    /// Sets how long `service.procedure` may run before it is cancelled,
+    /// overriding both the service and default timeouts
+    #[must_use]
+    pub fn procedure_timeout(
+        mut self,
+        service: impl Into<String>,
+        procedure: impl Into<String>,
+        timeout: Duration,
+    ) -> Self {
+        self.procedure_timeouts
+            .insert((service.into(), procedure.into()), timeout);
+        self
+    }
+
+    /// Limits how many procedures a single session may have running at once
+    ///
+    /// Procedures started past this limit are rejected with a
+    /// [`CANCEL`](crate::types::ProcedureError::Cancel) error instead of being
+    /// invoked. By default there is no limit.
+    #[must_use]
+    pub fn max_streams_per_session(mut self, max: usize) -> Self {
+        self.max_streams_per_session = Some(max);
+        self
+    }
+
+    /// Limits how many invocations of `service.procedure` may run at once
+    /// across all sessions
+    ///
+    /// Procedures started past this limit are rejected with a
+    /// [`CANCEL`](crate::types::ProcedureError::Cancel) error instead of being
+    /// invoked.
+    #[must_use]
+    pub fn procedure_concurrency_limit(
+        mut self,
+        service: impl Into<String>,
+        procedure: impl Into<String>,
+        max: usize,
+    ) -> Self {
+        self.procedure_concurrency_limits
+            .insert((service.into(), procedure.into()), max);
+        self
+    }
+
+    /// Limits the rate at which each session may send messages
+    ///
+    /// See [`RateLimit`] for the available limits and what happens once a
+    /// session exceeds them. By default sessions are not rate limited.
+    #[must_use]
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Lets each connection pick its own codec
+    ///
+    /// The [default codec](Self::codec) is used for connections that don't
+    /// pick one. See [`CodecNegotiation`] for the available methods.
+    #[must_use]
+    pub fn codec_negotiation(mut self, negotiation: CodecNegotiation) -> Self {
+        self.codec_negotiation = Some(negotiation);
+        self
+    }
+
+    /// Also accepts clients speaking protocol v1.1
+    ///
+    /// Messages from these clients are translated to and from v2.0, so
+    /// procedures don't need to know which version a client uses.
+    #[must_use]
+    pub fn v1_1_compatibility(mut self) -> Self {
+        self.v1_1_compatibility = true;
+        self
+    }
+
+    /// Limits how many messages procedures of a session may queue for sending
+    ///
+    /// Procedures wait to send once the queue is full. By default the queue
+    /// is unbounded.
+    #[must_use]
+    pub fn outgoing_channel_capacity(mut self, capacity: usize) -> Self {
+        self.outgoing_channel_capacity = Some(capacity);
+        self
+    }
+
+    /// Limits how many client messages may be queued for each procedure
+    ///
+    /// A procedure whose queue is full when another message arrives is
+    /// aborted, and the client receives a [`CANCEL`](crate::types::ProcedureError::Cancel)
+    /// error for its stream, so procedures must keep reading their messages.
+    /// By default the queue is unbounded.
+    #[must_use]
+    pub fn stream_channel_capacity(mut self, capacity: usize) -> Self {
+        self.stream_channel_capacity = Some(capacity);
+        self
+    }
+
+    /// Runs `hook` on every WebSocket request before it is upgraded
+    ///
+    /// Resolving to an error rejects the request with the returned response
+    /// instead of upgrading it, so origin checks and cookie based
+    /// authentication can answer with a regular HTTP status. The hook is
+    /// async, so it can look sessions up in a database:
+    ///
+    /// ```
+    /// # use rapids::{codecs::BinaryCodec, dispatch::{RiverServer, ServiceHandler}};
+    /// # use axum::{http::StatusCode, response::IntoResponse};
+    /// # fn builder<H: ServiceHandler>(handler: H) -> anyhow::Result<RiverServer<H>> {
+    /// RiverServer::builder()
+    ///     .codec(BinaryCodec {})
+    ///     .handler(handler)
+    ///     .on_upgrade(|request| async move {
+    ///         match request.origin() {
+    ///             Some("https://example.com") => Ok(()),
+    ///             _ => Err(StatusCode::FORBIDDEN.into_response()),
+    ///         }
+    ///     })
+    ///     .build()
+    /// # }
+    /// ```
+    ///
+    /// Only applies to WebSocket connections served with
+    /// [`delta`](RiverServer::delta) or [`serve`](RiverServer::serve).
+    #[must_use]
+    pub fn on_upgrade<F>(
+        mut self,
+        hook: impl Fn(UpgradeRequest) -> F + Send + Sync + 'static,
+    ) -> Self
+    where
+        F: Future<Output = Result<(), Response>> + Send + 'static,
+    {
+        self.hooks.upgrade = Some(Box::new(move |request| Box::pin(hook(request))));
+        self
+    }
+
+    /// Runs `hook` on every handshake that the server would otherwise accept
+    ///
+    /// Returning an error rejects the handshake with
+    /// [`REJECTED_BY_CUSTOM_HANDLER`](crate::types::HandshakeError::RejectedByCustomHandler)
+    /// and the returned message. This is where handshake metadata can be validated.
+    #[must_use]
+    pub fn on_handshake(
+        mut self,
+        hook: impl Fn(&HandshakeRequest) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.handshake = Some(Box::new(hook));
+        self
+    }
+
+    /// Sends the token returned by `hook` in every accepted handshake response
+    ///
+    /// Load balancers and proxies can read the token from the response to
+    /// route a reconnecting client back to the instance that owns its session,
+    /// which can be checked with [`RiverServer::owns_session`]. A token
+    /// identifying the instance, like its [id](RiverServer::server_id), is
+    /// usually enough. No token is sent if `hook` returns `None`.
+    #[must_use]
+    pub fn affinity_token(
+        mut self,
+        hook: impl Fn(&HandshakeRequest) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.affinity = Some(Box::new(hook));
+        self
+    }
+
+    /// Runs `hook` after every successful handshake
+    #[must_use]
+    pub fn on_connect(mut self, hook: impl Fn(&SessionInfo) + Send + Sync + 'static) -> Self {
+        self.hooks.connect = Some(Box::new(hook));
+        self
+    }
+
+    /// Runs `hook` whenever a client that completed its handshake disconnects
+    #[must_use]
+    pub fn on_disconnect(mut self, hook: impl Fn(&SessionInfo) + Send + Sync + 'static) -> Self {
+        self.hooks.disconnect = Some(Box::new(hook));
+        self
+    }
+
+    /// Validates the configuration and creates the server
+    ///
+    /// # Errors
+    /// Returns an error if the codec or handler are missing, or if any
+    /// settings are invalid or contradict each other.
+    #[allow(clippy::too_many_lines)]
+    pub fn build(self) -> Result<RiverServer<H>> {
+        let Some(codec) = self.codec else {
+            bail!("A codec is required");
+        };
+        let Some(handler) = self.handler else {
+            bail!("A handler is required");
+        };
+
+        if self.server_id.as_ref().is_some_and(String::is_empty) {
+            bail!("Server id must not be empty");
+        }
+
+        if let Some(heartbeats) = self.heartbeats_until_dead {
+            if heartbeats == 0 {
+                bail!("Heartbeats until dead must be at least 1");
+            }
+
+            if self.heartbeat_interval.is_zero() {
+                bail!("Heartbeats until dead requires heartbeats to be enabled");
+            }
+        }
+
+        let payload_sizes = self
+            .max_payload_size
+            .iter()
+            .chain(self.procedure_max_payload_sizes.values());
+
+        if self.max_frame_size == Some(0) || payload_sizes.clone().any(|&size| size == 0) {
+            bail!("Size limits must be at least 1 byte");
+        }
+
+        if let Some(frame) = self.max_frame_size {
+            if let Some(payload) = payload_sizes.max().filter(|&&payload| payload > frame) {
+                bail!("Max payload size ({payload}) is larger than max frame size ({frame})");
+            }
+        }
+
+        let mut timeouts = self
+            .default_timeout
+            .iter()
+            .chain(self.service_timeouts.values())
+            .chain(self.procedure_timeouts.values());
+
+        if timeouts.any(Duration::is_zero) {
+            bail!("Timeouts must be longer than zero");
+        }
+
+        if self.max_streams_per_session == Some(0)
+            || self
+                .procedure_concurrency_limits
+                .values()
+                .any(|&max| max == 0)
+        {
+            bail!("Concurrency limits must be at least 1");
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.validate()?;
+        }
+
+        if self.outgoing_channel_capacity == Some(0) || self.stream_channel_capacity == Some(0) {
+            bail!("Channel capacities must be at least 1");
+        }
+
+        if self.session_buffer_limit.messages == 0 || self.session_buffer_limit.bytes == 0 {
+            bail!("Session buffer limits must be at least 1");
+        }
+
+        let mut server = RiverServer::from_codec(codec, handler, self.heartbeat_interval);
+
+        // Settings for procedures that don't exist are most likely typos
+        let description = &server.service_description;
+        let procedures = self
+            .procedure_max_payload_sizes
+            .keys()
+            .chain(self.procedure_timeouts.keys())
+            .chain(self.procedure_concurrency_limits.keys());
+
+        for service in self.service_timeouts.keys() {
+            if !description.contains_key(service) {
+                bail!("Unknown service {service}");
+            }
+        }
+
+        for (service, procedure) in procedures {
+            if !description
+                .get(service)
+                .is_some_and(|procedures| procedures.contains(procedure))
+            {
+                bail!("Unknown procedure {service}.{procedure}");
+            }
+        }
+
+        if let Some(server_id) = self.server_id {
+            server.server_id = server_id;
+        }
+
+        server.heartbeats_until_dead = self.heartbeats_until_dead;
+        server.handshake_timeout = self.handshake_timeout;
+        server.session_grace_period = self.session_grace_period;
+        server.session_buffer_limit = self.session_buffer_limit;
+
+        if let Some(store) = self.session_store {
+            server.session_store = store;
+        }
+
+        server.max_frame_size = self.max_frame_size;
+        server.default_max_payload_size = self.max_payload_size;
+        server.procedure_max_payload_sizes = self.procedure_max_payload_sizes;
+        server.default_timeout = self.default_timeout;
+        server.service_timeouts = self.service_timeouts;
+        server.procedure_timeouts = self.procedure_timeouts;
+        server.max_streams_per_session = self.max_streams_per_session;
+        server.procedure_limits = self
+            .procedure_concurrency_limits
+            .into_iter()
+            .map(|(procedure, max)| (procedure, ConcurrencyLimit::new(Some(max))))
+            .collect();
+        server.rate_limit = self.rate_limit;
+        server.codec_negotiation = self.codec_negotiation;
+        server.v1_1_compatibility = self.v1_1_compatibility;
+        server.outgoing_channel_capacity = self.outgoing_channel_capacity;
+        server.stream_channel_capacity = self.stream_channel_capacity;
+        server.hooks = self.hooks;
+
+        Ok(server)
+    }
+}
@@ -0,0 +1,345 @@
+//! Tracks the sessions served by a server
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use bytes::Bytes;
+use kanal::AsyncSender;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    limits::ConcurrencyLimit,
+    sessions::{BufferLimit, BufferedMessage, SessionState},
+    types::{OutgoingMessage, StreamInfo},
+};
+
+/// Session ids of the clients a server is serving
+///
+/// Sessions are owned from their handshake until their procedures are
+/// cancelled, which includes the session grace period.
+#[derive(Clone, Default)]
+pub(crate) struct OwnedSessions {
+    /// Number of connections holding each session id
+    ids: Arc<Mutex<HashMap<String, usize>>>,
+    /// Procedures of disconnected sessions, waiting for their client to resume
+    parked: Arc<Mutex<HashMap<String, ParkedSession>>>,
+    /// Connections serving each session, which let go of it when it resumes elsewhere
+    attached: Arc<Mutex<HashMap<String, Attachment>>>,
+    /// Tells apart the times a session was parked or attached
+    next_generation: Arc<AtomicU64>,
+}
+
+/// Asks a connection to let go of its session, answering once it has
+pub(crate) type DetachRequest = oneshot::Sender<()>;
+
+/// The connection serving a session
+struct Attachment {
+    client_id: String,
+    generation: u64,
+    detach: mpsc::Sender<DetachRequest>,
+}
+
+/// The procedures of a disconnected session
+struct ParkedSession {
+    procedures: SessionProcedures,
+    generation: u64,
+    _claim: SessionClaim,
+}
+
+impl OwnedSessions {
+    fn ids(&self) -> MutexGuard<'_, HashMap<String, usize>> {
+        self.ids.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn parked(&self) -> MutexGuard<'_, HashMap<String, ParkedSession>> {
+        self.parked.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn attached(&self) -> MutexGuard<'_, HashMap<String, Attachment>> {
+        self.attached.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Records that a connection serves `session_id` for `client_id`
+    ///
+    /// The connection must let go of the session when asked to through the
+    /// returned receiver, and stays attached until the [`AttachedSession`]
+    /// is dropped.
+    pub(crate) fn attach(
+        &self,
+        session_id: String,
+        client_id: String,
+    ) -> (AttachedSession, mpsc::Receiver<DetachRequest>) {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let (detach, requests) = mpsc::channel(1);
+
+        self.attached().insert(
+            session_id.clone(),
+            Attachment {
+                client_id,
+                generation,
+                detach,
+            },
+        );
+
+        let attached = AttachedSession {
+            sessions: self.clone(),
+            session_id,
+            generation,
+        };
+
+        (attached, requests)
+    }
+
+    /// Makes the connection serving `session_id` for `client_id` let go of it
+    ///
+    /// Resolves once the connection has saved the session and parked its
+    /// procedures, or right away if no connection serves the session.
+    pub(crate) async fn detach(&self, session_id: &str, client_id: &str) {
+        let detach = self
+            .attached()
+            .get(session_id)
+            .filter(|attachment| attachment.client_id == client_id)
+            .map(|attachment| attachment.detach.clone());
+        let Some(detach) = detach else {
+            return;
+        };
+
+        let (request, detached) = oneshot::channel();
+
+        // Connections that already ended drop the request
+        if detach.send(request).await.is_ok() {
+            detached.await.ok();
+        }
+    }
+
+    /// Keeps the procedures of a disconnected session until it resumes or expires
+    ///
+    /// Returns the generation to [expire](Self::expire) them with, and any
+    /// procedures that were parked for the session before.
+    pub(crate) fn park(
+        &self,
+        claim: SessionClaim,
+        procedures: SessionProcedures,
+    ) -> (u64, Option<SessionProcedures>) {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let session_id = claim.session_id.clone();
+        let parked = ParkedSession {
+            procedures,
+            generation,
+            _claim: claim,
+        };
+
+        let previous = self.parked().insert(session_id, parked);
+
+        (generation, previous.map(|parked| parked.procedures))
+    }
+
+    /// Takes the parked procedures of `session_id`, if it has any
+    pub(crate) fn unpark(&self, session_id: &str) -> Option<SessionProcedures> {
+        self.parked()
+            .remove(session_id)
+            .map(|parked| parked.procedures)
+    }
+
+    /// Takes the procedures parked as `generation`, unless a client took them over
+    pub(crate) fn expire(&self, session_id: &str, generation: u64) -> Option<SessionProcedures> {
+        let mut parked = self.parked();
+
+        if parked.get(session_id)?.generation != generation {
+            return None;
+        }
+
+        parked.remove(session_id).map(|parked| parked.procedures)
+    }
+
+    /// Returns whether `session_id` is owned
+    pub(crate) fn contains(&self, session_id: &str) -> bool {
+        self.ids().contains_key(session_id)
+    }
+
+    /// Takes ownership of `session_id` until the returned [`SessionClaim`] is dropped
+    pub(crate) fn claim(&self, session_id: String) -> SessionClaim {
+        *self.ids().entry(session_id.clone()).or_default() += 1;
+
+        SessionClaim {
+            sessions: self.clone(),
+            session_id,
+        }
+    }
+}
+
+/// Ownership of a session, given up when dropped
+pub(crate) struct SessionClaim {
+    sessions: OwnedSessions,
+    session_id: String,
+}
+
+impl Drop for SessionClaim {
+    fn drop(&mut self) {
+        let mut ids = self.sessions.ids();
+
+        if let Some(count) = ids.get_mut(&self.session_id) {
+            *count -= 1;
+
+            if *count == 0 {
+                ids.remove(&self.session_id);
+            }
+        }
+    }
+}
+
+/// A connection serving a session, detached when dropped
+pub(crate) struct AttachedSession {
+    sessions: OwnedSessions,
+    session_id: String,
+    generation: u64,
+}
+
+impl Drop for AttachedSession {
+    fn drop(&mut self) {
+        let mut attached = self.sessions.attached();
+
+        // The session may have been attached to a newer connection since
+        if attached
+            .get(&self.session_id)
+            .is_some_and(|attachment| attachment.generation == self.generation)
+        {
+            attached.remove(&self.session_id);
+        }
+    }
+}
+
+/// The running procedures of a session, which outlive its connections
+pub(crate) struct SessionProcedures {
+    /// Procedures that accept messages from the client, by stream id
+    pub(crate) streams: HashMap<String, StreamInfo>,
+    /// Given to procedures to send messages to the client
+    pub(crate) send: AsyncSender<OutgoingMessage>,
+    /// Messages from procedures, in the order they were sent
+    pub(crate) outgoing: mpsc::Receiver<OutgoingMessage>,
+    /// Limits the procedures running at once
+    pub(crate) limit: ConcurrencyLimit,
+}
+
+/// Sequence numbers and unacknowledged messages of a connected session
+pub(crate) struct SessionProgress {
+    next_sent_seq: i32,
+    next_expected_seq: i32,
+    /// Messages below this `seq` were handled before the session resumed
+    retransmitted_below: i32,
+    /// Sent messages kept until acknowledged, only if the session is resumable
+    unacked: Option<VecDeque<(i32, Bytes)>>,
+    /// Total size of the unacknowledged messages
+    unacked_bytes: usize,
+    limit: BufferLimit,
+}
+
+impl SessionProgress {
+    /// Starts a new session, buffering unacknowledged messages if it is `resumable`
+    pub(crate) fn new(resumable: bool, limit: BufferLimit) -> Self {
+        Self {
+            next_sent_seq: 0,
+            next_expected_seq: 0,
+            retransmitted_below: 0,
+            unacked: resumable.then(VecDeque::new),
+            unacked_bytes: 0,
+            limit,
+        }
+    }
+
+    /// Continues a session from its stored `state`
+    pub(crate) fn resume(state: SessionState, limit: BufferLimit) -> Self {
+        let unacked: VecDeque<_> = state
+            .unacked
+            .into_iter()
+            .map(|message| (message.seq, Bytes::from(message.data)))
+            .collect();
+
+        Self {
+            next_sent_seq: state.next_sent_seq,
+            next_expected_seq: state.next_expected_seq,
+            retransmitted_below: state.next_expected_seq,
+            unacked_bytes: unacked.iter().map(|(_, data)| data.len()).sum(),
+            unacked: Some(unacked),
+            limit,
+        }
+    }
+
+    /// Messages sent before the session resumed that have to be sent again
+    pub(crate) fn unacked(&self) -> impl Iterator<Item = &Bytes> {
+        self.unacked.iter().flatten().map(|(_, data)| data)
+    }
+
+    /// Records a message from the client
+    ///
+    /// Returns `false` if the message was already handled before the
+    /// session resumed, in which case it must be skipped.
+    pub(crate) fn receive(&mut self, seq: i32, ack: i32) -> bool {
+        if seq < self.retransmitted_below {
+            return false;
+        }
+
+        self.next_expected_seq = self.next_expected_seq.max(seq.saturating_add(1));
+
+        if let Some(unacked) = &mut self.unacked {
+            while unacked.front().is_some_and(|(seq, _)| *seq < ack) {
+                if let Some((_, data)) = unacked.pop_front() {
+                    self.unacked_bytes -= data.len();
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Takes the `seq` for the next message sent to the client
+    pub(crate) fn next_seq(&mut self) -> i32 {
+        let seq = self.next_sent_seq;
+        self.next_sent_seq += 1;
+        seq
+    }
+
+    /// The `ack` to send, which is the `seq` expected next from the client
+    pub(crate) fn ack(&self) -> i32 {
+        self.next_expected_seq
+    }
+
+    /// Records that the message with `seq` was sent
+    pub(crate) fn sent(&mut self, seq: i32, data: &Bytes) {
+        if let Some(unacked) = &mut self.unacked {
+            unacked.push_back((seq, data.clone()));
+            self.unacked_bytes += data.len();
+        }
+    }
+
+    /// Returns whether more messages are unacknowledged than the session may buffer
+    pub(crate) fn buffer_full(&self) -> bool {
+        self.unacked.as_ref().is_some_and(|unacked| {
+            unacked.len() > self.limit.messages || self.unacked_bytes > self.limit.bytes
+        })
+    }
+
+    /// Captures the progress so the session can be stored
+    pub(crate) fn state(&self, session_id: &str, client_id: &str) -> SessionState {
+        SessionState {
+            session_id: session_id.to_string(),
+            client_id: client_id.to_string(),
+            next_sent_seq: self.next_sent_seq,
+            next_expected_seq: self.next_expected_seq,
+            unacked: self
+                .unacked
+                .iter()
+                .flatten()
+                .map(|(seq, data)| BufferedMessage {
+                    seq: *seq,
+                    data: data.to_vec(),
+                })
+                .collect(),
+        }
+    }
+}
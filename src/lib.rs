@@ -10,7 +10,12 @@
 //! are two can be found in the [`types`] page.
 
 pub mod codecs;
+mod compat;
 pub mod dispatch;
+pub mod limits;
+pub mod pubsub;
+pub mod sessions;
+pub mod transport;
 pub mod types;
 pub mod utils;
 
@@ -0,0 +1,196 @@
+//! Limits the dispatcher applies to clients
+//!
+//! Most limits are configured directly on [`RiverServerBuilder`](crate::dispatch::RiverServerBuilder),
+//! this module contains the types needed for the more involved ones.
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+
+/// Counts running procedures against a maximum
+#[derive(Clone)]
+pub(crate) struct ConcurrencyLimit {
+    max: Option<usize>,
+    active: Arc<AtomicUsize>,
+}
+
+impl ConcurrencyLimit {
+    /// Creates a limit that allows at most `max` procedures, or any amount if `None`
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a slot, returning `None` if the limit has been reached
+    ///
+    /// The slot is given back when the returned [`ConcurrencySlot`] is dropped.
+    pub(crate) fn acquire(&self) -> Option<ConcurrencySlot> {
+        let max = self.max.unwrap_or(usize::MAX);
+
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| ConcurrencySlot {
+                active: self.active.clone(),
+            })
+    }
+}
+
+/// A running procedure counted by a [`ConcurrencyLimit`]
+pub(crate) struct ConcurrencySlot {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConcurrencySlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// What the dispatcher does when a session exceeds its [`RateLimit`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Stop reading from the session until it is back within its limit
+    Delay,
+    /// Keep serving existing streams, but reject new procedures with a
+    /// [`CANCEL`](crate::types::ProcedureError::Cancel) error
+    RejectStreams,
+    /// Close the connection with a policy violation
+    Disconnect,
+}
+
+/// Token bucket limits for inbound messages of a single session
+///
+/// Each limit allows bursts of up to one second worth of traffic, after which
+/// the session has to slow down to the configured rate. Rates are validated
+/// when the server is [built](crate::dispatch::RiverServerBuilder::build).
+///
+/// ```
+/// # use rapids::limits::{RateLimit, RateLimitAction};
+/// let rate_limit = RateLimit::new(RateLimitAction::Delay)
+///     .messages_per_second(100.0)
+///     .bytes_per_second(1024.0 * 1024.0);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    messages_per_second: Option<f64>,
+    bytes_per_second: Option<f64>,
+    action: RateLimitAction,
+}
+
+impl RateLimit {
+    /// Creates a rate limit that applies `action` once exceeded
+    ///
+    /// Messages and bytes are both unlimited until their rates are set.
+    #[must_use]
+    pub fn new(action: RateLimitAction) -> Self {
+        Self {
+            messages_per_second: None,
+            bytes_per_second: None,
+            action,
+        }
+    }
+
+    /// Limits how many messages per second the session may send
+    #[must_use]
+    pub fn messages_per_second(mut self, rate: f64) -> Self {
+        self.messages_per_second = Some(rate);
+        self
+    }
+
+    /// Limits how many bytes per second the session may send
+    #[must_use]
+    pub fn bytes_per_second(mut self, rate: f64) -> Self {
+        self.bytes_per_second = Some(rate);
+        self
+    }
+
+    /// Checks that every rate is a positive, finite number
+    pub(crate) fn validate(&self) -> Result<()> {
+        for rate in [self.messages_per_second, self.bytes_per_second]
+            .into_iter()
+            .flatten()
+        {
+            if !rate.is_finite() || rate <= 0.0 {
+                bail!("Rate limits must be positive, got {rate}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The action taken once the limit is exceeded
+    pub fn action(&self) -> RateLimitAction {
+        self.action
+    }
+
+    pub(crate) fn limiter(&self) -> RateLimiter {
+        RateLimiter {
+            messages: self.messages_per_second.map(TokenBucket::new),
+            bytes: self.bytes_per_second.map(TokenBucket::new),
+        }
+    }
+}
+
+/// Per-session state of a [`RateLimit`]
+pub(crate) struct RateLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Accounts for a message of `len` bytes
+    ///
+    /// Returns how long the session has to wait before it is back within
+    /// its limits, or `None` if it already is.
+    pub(crate) fn record(&mut self, len: usize) -> Option<Duration> {
+        let now = Instant::now();
+
+        #[allow(clippy::cast_precision_loss, reason = "Precision is not needed")]
+        let waits = [
+            self.messages.as_mut().and_then(|b| b.take(1.0, now)),
+            self.bytes.as_mut().and_then(|b| b.take(len as f64, now)),
+        ];
+
+        waits.into_iter().flatten().max()
+    }
+}
+
+/// Token bucket that allows its tokens to go into debt
+///
+/// Going into debt means a single oversized message is still accepted, but
+/// pushes the wait time further out.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, amount: f64, now: Instant) -> Option<Duration> {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - amount;
+
+        (self.tokens < 0.0)
+            .then(|| Duration::try_from_secs_f64(-self.tokens / self.rate).unwrap_or(Duration::MAX))
+    }
+}
@@ -0,0 +1,224 @@
+//! Topic based publish/subscribe helpers for `subscription` procedures
+//!
+//! Most subscriptions follow the same shape: the procedure registers
+//! interest in some named event source, and other parts of the
+//! application push updates to every registered client. [`PubSub`]
+//! handles the bookkeeping for this pattern, including cleaning up
+//! subscribers once their client closes the stream or disconnects.
+//!
+//! # Usage
+//! A `subscription` handler hands its stream over to the topic with
+//! [`PubSub::subscribe`], which resolves once the subscription has
+//! ended. Any code holding the [`PubSub`] (usually behind an [`Arc`](std::sync::Arc))
+//! can then call [`PubSub::publish`] to send a value to every subscriber.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::Result;
+use kanal::{AsyncReceiver, AsyncSender};
+use serde::Serialize;
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    types::{IncomingMessage, OutgoingMessage, Payload, ProcedureRes, RPCMetadata},
+    utils::{ok_payload, payload_to_msg},
+};
+
+/// A stream that is currently subscribed to a topic
+struct Subscriber {
+    metadata: RPCMetadata,
+    channel: AsyncSender<OutgoingMessage>,
+}
+
+/// Subscribers are keyed by `(client_id, stream_id)` as stream ids are only
+/// unique per client.
+type SubscriberKey = (String, String);
+
+/// Removes a stream from its topic once its subscription is dropped
+struct Subscription<'a> {
+    pubsub: &'a PubSub,
+    topic: &'a str,
+    key: SubscriberKey,
+    metadata: &'a RPCMetadata,
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        self.pubsub.remove(self.topic, &self.key);
+
+        debug!(
+            topic = self.topic,
+            stream_id = self.metadata.stream_id,
+            client_id = self.metadata.client_id,
+            "Unsubscribed from topic"
+        );
+    }
+}
+
+/// Reason a [`PubSub::subscribe`] call finished
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubscriptionEnd {
+    /// The client closed the stream, the handler should respond by closing
+    /// its side of the stream, usually with [`ProcedureRes::Close`].
+    Closed,
+    /// The client disconnected, nothing more can be sent on this stream.
+    Disconnected,
+}
+
+/// Registry of named topics and the streams subscribed to them
+///
+/// See the [module level documentation](self) for more information.
+#[derive(Default)]
+pub struct PubSub {
+    topics: Mutex<HashMap<String, HashMap<SubscriberKey, Subscriber>>>,
+}
+
+impl PubSub {
+    /// Creates an empty registry with no topics
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn topics(&self) -> MutexGuard<'_, HashMap<String, HashMap<SubscriberKey, Subscriber>>> {
+        // Subscriber maps are always left in a consistent state, so a poisoned
+        // lock is still safe to use.
+        self.topics.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Subscribes the stream described by `metadata` to `topic`
+    ///
+    /// The returned future resolves once the subscription is over, which
+    /// happens when the client closes the stream, the client disconnects,
+    /// or the stream's receiver is dropped. The stream is removed from the
+    /// topic before this returns, or as soon as the future is dropped.
+    ///
+    /// The returned [`SubscriptionEnd`] tells the handler whether it still
+    /// needs to close the stream.
+    pub async fn subscribe(
+        &self,
+        topic: &str,
+        metadata: &RPCMetadata,
+        channel: AsyncSender<OutgoingMessage>,
+        recv: AsyncReceiver<IncomingMessage>,
+    ) -> SubscriptionEnd {
+        let key = (metadata.client_id.clone(), metadata.stream_id.clone());
+
+        self.topics().entry(topic.to_string()).or_default().insert(
+            key.clone(),
+            Subscriber {
+                metadata: metadata.clone(),
+                channel,
+            },
+        );
+
+        debug!(
+            topic,
+            stream_id = metadata.stream_id,
+            client_id = metadata.client_id,
+            "Subscribed to topic"
+        );
+
+        // Removes the stream even if the handler is aborted before the
+        // subscription ends, which happens to handlers that stop reading
+        let _subscription = Subscription {
+            pubsub: self,
+            topic,
+            key,
+            metadata,
+        };
+
+        loop {
+            match recv.recv().await {
+                Ok(IncomingMessage::Close) => break SubscriptionEnd::Closed,
+                Ok(IncomingMessage::ForceClose) | Err(_) => break SubscriptionEnd::Disconnected,
+                Ok(IncomingMessage::Request(_)) => {
+                    warn!(
+                        topic,
+                        stream_id = metadata.stream_id,
+                        "Ignoring request sent to a subscription"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Publishes `value` to every stream subscribed to `topic`
+    ///
+    /// The value is sent as a successful result, the same way a procedure
+    /// response would be. Subscribers whose connection has already gone
+    /// away are removed from the topic. Publishing never waits on a
+    /// subscriber, the value is skipped for subscribers whose
+    /// [outgoing queue](crate::dispatch::RiverServerBuilder::outgoing_channel_capacity)
+    /// is full so a slow client can't hold up the others.
+    ///
+    /// Returns the number of subscribers the value was delivered to.
+    ///
+    /// # Errors
+    /// Returns an error if `value` could not be serialized.
+    pub fn publish<T>(&self, topic: &str, value: &T) -> Result<usize>
+    where
+        T: ?Sized + Serialize,
+    {
+        let payload = ok_payload(Payload::new(value)?);
+
+        let subscribers: Vec<(SubscriberKey, RPCMetadata, AsyncSender<OutgoingMessage>)> =
+            match self.topics().get(topic) {
+                Some(subscribers) => subscribers
+                    .iter()
+                    .map(|(key, sub)| (key.clone(), sub.metadata.clone(), sub.channel.clone()))
+                    .collect(),
+                None => return Ok(0),
+            };
+
+        let mut delivered = 0;
+
+        for (key, metadata, channel) in subscribers {
+            let message = payload_to_msg(
+                ProcedureRes::Response(payload.clone()),
+                &metadata,
+                false,
+                false,
+            );
+
+            match channel.try_send(message) {
+                Ok(true) => delivered += 1,
+                Ok(false) => warn!(
+                    topic,
+                    stream_id = metadata.stream_id,
+                    "Subscriber is not keeping up, skipping published value"
+                ),
+                Err(_) => {
+                    debug!(
+                        topic,
+                        stream_id = metadata.stream_id,
+                        "Dropping subscriber with closed connection"
+                    );
+                    self.remove(topic, &key);
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    /// Returns the number of streams currently subscribed to `topic`
+    pub fn subscriber_count(&self, topic: &str) -> usize {
+        self.topics().get(topic).map_or(0, HashMap::len)
+    }
+
+    fn remove(&self, topic: &str, key: &SubscriberKey) {
+        let mut topics = self.topics();
+
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.remove(key);
+
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+    }
+}
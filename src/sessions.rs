@@ -0,0 +1,250 @@
+//! Storage for resumable sessions
+//!
+//! A client that loses its connection can handshake again with the same
+//! session id and pick up where it left off, as long as the server still
+//! knows the session's [`SessionState`]: the sequence numbers both sides
+//! have reached, and the messages the client has not acknowledged yet.
+//! The server resends those messages after the client resumes.
+//!
+//! Sessions are resumable while their
+//! [grace period](crate::dispatch::RiverServerBuilder::session_grace_period)
+//! lasts, and their state is kept in a [`SessionStore`]. The default
+//! [`MemorySessionStore`] only lives as long as the server process, while
+//! a persistent or shared store like [`FileSessionStore`] lets sessions
+//! resume on a restarted server or another instance.
+//!
+//! Only a [limited](BufferLimit) amount of unacknowledged messages is kept,
+//! a session whose client falls further behind is ended.
+//!
+//! Procedures keep running while the client is away. A session resuming on
+//! the same server takes them over, including the messages they sent in the
+//! meantime, otherwise they are cancelled once the grace period ends.
+//! Clients may also resume before the server has noticed that their previous
+//! connection is gone, in which case that connection is closed and the
+//! session carries on from where it left off.
+//!
+//! # Limitations
+//! Procedures only live on the server that started them, a session resuming
+//! on another instance loses them and the client is not told about it.
+//! The state is saved once per heartbeat and when the client disconnects,
+//! so a server that crashes may lose the latest messages of a session, in
+//! which case the client is told to start a new session. Clients must
+//! resume with the codec their session started with.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::types::ExpectedSessionState;
+
+/// Everything needed to resume a session
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionState {
+    /// The id of the session
+    pub session_id: String,
+    /// The id of the client that owns the session
+    pub client_id: String,
+    /// The `seq` of the next message sent to the client
+    pub next_sent_seq: i32,
+    /// The `seq` of the next message expected from the client
+    pub next_expected_seq: i32,
+    /// Messages the client has not acknowledged, oldest first
+    pub unacked: Vec<BufferedMessage>,
+}
+
+/// How much a resumable session may buffer for its client
+///
+/// Sessions whose client doesn't acknowledge messages fast enough are ended
+/// once either limit is exceeded, see
+/// [`RiverServerBuilder::session_buffer_limit`](crate::dispatch::RiverServerBuilder::session_buffer_limit).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferLimit {
+    /// The most unacknowledged messages kept
+    pub messages: usize,
+    /// The most bytes of unacknowledged messages kept
+    pub bytes: usize,
+}
+
+impl Default for BufferLimit {
+    /// 1024 messages or 16 MiB
+    fn default() -> Self {
+        Self {
+            messages: 1024,
+            bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A message sent to a client, encoded with the session's codec
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BufferedMessage {
+    /// The `seq` the message was sent with
+    pub seq: i32,
+    /// The encoded message, stored as base64
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+impl SessionState {
+    /// Returns whether a client expecting `expected` can resume this session
+    ///
+    /// The client must not expect messages that were never sent or were
+    /// already acknowledged, and must not have skipped any of its own.
+    pub fn can_resume(&self, expected: &ExpectedSessionState) -> bool {
+        let oldest_unacked = self
+            .unacked
+            .first()
+            .map_or(self.next_sent_seq, |message| message.seq);
+
+        (i64::from(oldest_unacked)..=i64::from(self.next_sent_seq))
+            .contains(&expected.next_expected_seq)
+            && expected.next_sent_seq >= i64::from(self.next_expected_seq)
+    }
+}
+
+/// Stores the state of sessions so they can be resumed
+///
+/// Stores are called on tokio's blocking thread pool, so they may do
+/// blocking IO, but the session waits for each call to finish. Errors are
+/// logged and otherwise treated as if the session could not be found.
+pub trait SessionStore: Send + Sync {
+    /// Returns the state of `session_id`, if it is stored
+    ///
+    /// # Errors
+    /// Returns an error if the store can't be read.
+    fn load(&self, session_id: &str) -> Result<Option<SessionState>>;
+
+    /// Stores `state`, replacing any previous state of the session
+    ///
+    /// # Errors
+    /// Returns an error if the store can't be written.
+    fn save(&self, state: &SessionState) -> Result<()>;
+
+    /// Removes the state of `session_id`, doing nothing if it isn't stored
+    ///
+    /// # Errors
+    /// Returns an error if the store can't be written.
+    fn remove(&self, session_id: &str) -> Result<()>;
+}
+
+/// Keeps sessions in memory, the default [`SessionStore`]
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, SessionState>>,
+}
+
+impl MemorySessionStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, SessionState>> {
+        self.sessions.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, session_id: &str) -> Result<Option<SessionState>> {
+        Ok(self.sessions().get(session_id).cloned())
+    }
+
+    fn save(&self, state: &SessionState) -> Result<()> {
+        self.sessions()
+            .insert(state.session_id.clone(), state.clone());
+        Ok(())
+    }
+
+    fn remove(&self, session_id: &str) -> Result<()> {
+        self.sessions().remove(session_id);
+        Ok(())
+    }
+}
+
+/// Keeps each session in a JSON file inside a directory
+///
+/// Meant for testing restarts and for single machine deployments, where
+/// a restarted server can resume the sessions of its previous run.
+pub struct FileSessionStore {
+    dir: PathBuf,
+}
+
+impl FileSessionStore {
+    /// Creates a store in `dir`, creating the directory if needed
+    ///
+    /// # Errors
+    /// Returns an error if the directory can't be created.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self { dir })
+    }
+
+    fn path(&self, session_id: &str) -> Result<PathBuf> {
+        // Session ids are chosen by clients, so they must not escape the directory
+        if session_id.is_empty()
+            || !session_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Session id {session_id:?} can't be used as a file name");
+        }
+
+        Ok(self.dir.join(format!("{session_id}.json")))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, session_id: &str) -> Result<Option<SessionState>> {
+        match fs::read(self.path(session_id)?) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, state: &SessionState) -> Result<()> {
+        let path = self.path(&state.session_id)?;
+        let partial = path.with_extension("json.partial");
+
+        // Written to the side first so a crash never leaves a truncated file
+        fs::write(&partial, serde_json::to_vec(state)?)?;
+        fs::rename(partial, path)?;
+
+        Ok(())
+    }
+
+    fn remove(&self, session_id: &str) -> Result<()> {
+        match fs::remove_file(self.path(session_id)?) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Stores bytes as base64 strings, as JSON would write an array of numbers
+mod base64_bytes {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub(super) fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
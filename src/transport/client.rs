@@ -0,0 +1,254 @@
+//! Minimal client for talking to a River server over any transport
+
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+
+use super::{CloseReason, Frame, Transport};
+use crate::{
+    types::{
+        Control, ExpectedSessionState, HandshakeError, HandshakeRequest, HandshakeResponseOk,
+        Header, Payload, RawPayload, RequestInner, RiverResult, TransportCodec,
+        TransportControlMessage, TransportRequestMessage,
+    },
+    utils::generate_id,
+};
+
+/// A message received by a [`RawClient`]
+#[derive(Clone)]
+pub struct ReceivedMessage {
+    /// The message header
+    pub header: Header,
+    /// The message payload, decode it with [`RawPayload::decode`]
+    pub payload: RawPayload,
+}
+
+/// A minimal client that exchanges River messages over any [`Transport`]
+///
+/// This is not a full River client, it only encodes and decodes messages.
+/// Helpers fill in headers with increasing sequence numbers and
+/// acknowledge messages received with [`recv`](Self::recv), while
+/// [`send_frame`](Self::send_frame) and [`recv_frame`](Self::recv_frame)
+/// give access to the raw frames.
+pub struct RawClient<T: Transport> {
+    transport: T,
+    codec: Arc<dyn TransportCodec>,
+    client_id: String,
+    session_id: String,
+    server_id: Option<String>,
+    seq: i32,
+    next_expected_seq: i32,
+}
+
+impl<T: Transport> RawClient<T> {
+    /// Creates a client that talks to a server over `transport` with `codec`
+    ///
+    /// The client still has to [`handshake`](Self::handshake) before
+    /// calling procedures.
+    pub fn new(transport: T, codec: Arc<dyn TransportCodec>) -> Self {
+        Self {
+            transport,
+            codec,
+            client_id: generate_id(),
+            session_id: generate_id(),
+            server_id: None,
+            seq: 0,
+            next_expected_seq: 0,
+        }
+    }
+
+    /// The transport this client is connected over
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// The id this client identifies itself with
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// The id of the session this client handshakes with
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// The id of the server, known once it has responded to the handshake
+    pub fn server_id(&self) -> Option<&str> {
+        self.server_id.as_deref()
+    }
+
+    /// Creates a header for the next message sent on `stream_id`
+    pub fn header(&mut self, stream_id: impl Into<String>, control_flags: i32) -> Header {
+        let header = Header {
+            id: generate_id(),
+            from: self.client_id.clone(),
+            // Servers don't check the recipient before they are known
+            to: self.server_id.as_deref().unwrap_or("SERVER").to_string(),
+            seq: self.seq,
+            ack: self.next_expected_seq,
+            stream_id: stream_id.into(),
+            control_flags,
+        };
+
+        self.seq += 1;
+        header
+    }
+
+    /// Performs a handshake, returning the server's response
+    ///
+    /// The first handshake starts a new session, later ones ask the server
+    /// to resume it with the messages sent and received so far.
+    ///
+    /// # Errors
+    /// Returns an error if the connection closes or the server responds
+    /// with something other than a handshake response.
+    pub async fn handshake(&mut self) -> Result<RiverResult<HandshakeResponseOk, HandshakeError>> {
+        let request = Control::HandshakeRequest(HandshakeRequest {
+            protocol_version: crate::PROTOCOL_VERSION,
+            session_id: self.session_id.clone(),
+            expected_session_state: ExpectedSessionState {
+                next_expected_seq: self.next_expected_seq.into(),
+                next_sent_seq: self.seq.into(),
+            },
+            metadata: None,
+        });
+        self.send_control(generate_id(), request, 0).await?;
+
+        let Some(frame) = self.recv_frame().await else {
+            bail!("Connection closed during handshake");
+        };
+        let frame = frame?;
+
+        let message = self.codec.decode_control(&frame.data)?;
+
+        match message.payload {
+            Control::HandshakeResponse(response) => {
+                self.server_id = Some(message.header.from);
+                response.status.try_into()
+            }
+            payload => bail!("Expected a handshake response, got {payload:?}"),
+        }
+    }
+
+    /// Switches to a new `transport` and resumes the session over it
+    ///
+    /// # Errors
+    /// Returns an error if the handshake fails, see [`handshake`](Self::handshake).
+    pub async fn reconnect(
+        &mut self,
+        transport: T,
+    ) -> Result<RiverResult<HandshakeResponseOk, HandshakeError>> {
+        self.transport = transport;
+        self.handshake().await
+    }
+
+    /// Closes the connection without ending the session
+    ///
+    /// # Errors
+    /// Returns an error if the connection has already closed.
+    pub async fn close(&mut self) -> Result<()> {
+        self.transport.close(CloseReason::Normal).await
+    }
+
+    /// Sends the first message of a stream, invoking `service.procedure`
+    ///
+    /// # Errors
+    /// Returns an error if the message can't be encoded or the connection has closed.
+    pub async fn send_init(
+        &mut self,
+        stream_id: impl Into<String>,
+        service: impl Into<String>,
+        procedure: impl Into<String>,
+        payload: Payload,
+        control_flags: i32,
+    ) -> Result<()> {
+        let message = TransportRequestMessage {
+            header: self.header(stream_id, control_flags),
+            inner: RequestInner::Init {
+                service_name: service.into(),
+                procedure_name: procedure.into(),
+                payload,
+            },
+        };
+
+        let data = self.codec.encode_request(&message)?;
+        self.send_frame(Frame::binary(data)).await
+    }
+
+    /// Sends a message on a stream that has already been opened
+    ///
+    /// # Errors
+    /// Returns an error if the message can't be encoded or the connection has closed.
+    pub async fn send_request(
+        &mut self,
+        stream_id: impl Into<String>,
+        payload: Payload,
+        control_flags: i32,
+    ) -> Result<()> {
+        let message = TransportRequestMessage {
+            header: self.header(stream_id, control_flags),
+            inner: RequestInner::Request { payload },
+        };
+
+        let data = self.codec.encode_request(&message)?;
+        self.send_frame(Frame::binary(data)).await
+    }
+
+    /// Sends a control message
+    ///
+    /// # Errors
+    /// Returns an error if the message can't be encoded or the connection has closed.
+    pub async fn send_control(
+        &mut self,
+        stream_id: impl Into<String>,
+        payload: Control,
+        control_flags: i32,
+    ) -> Result<()> {
+        let message = TransportControlMessage {
+            header: self.header(stream_id, control_flags),
+            payload,
+        };
+
+        let data = self.codec.encode_control(&message)?;
+        self.send_frame(Frame::binary(data)).await
+    }
+
+    /// Sends a frame as-is
+    ///
+    /// # Errors
+    /// Returns an error if the connection has closed.
+    pub async fn send_frame(&mut self, frame: Frame) -> Result<()> {
+        self.transport.send(frame).await
+    }
+
+    /// Receives the next frame as-is, returning `None` once the server disconnects
+    ///
+    /// Transport errors are returned rather than treated as a disconnect.
+    pub async fn recv_frame(&mut self) -> Option<Result<Frame>> {
+        self.transport.recv().await
+    }
+
+    /// Receives and decodes the next message, including heartbeats
+    ///
+    /// Returns `None` once the server disconnects.
+    ///
+    /// # Errors
+    /// Returns an error if the transport fails or the message can't be decoded.
+    pub async fn recv(&mut self) -> Result<Option<ReceivedMessage>> {
+        let Some(frame) = self.recv_frame().await else {
+            return Ok(None);
+        };
+        let frame = frame?;
+
+        let decoded = self.codec.decode_frame(&frame.data)?;
+        self.next_expected_seq = self
+            .next_expected_seq
+            .max(decoded.header.seq.saturating_add(1));
+        let payload = RawPayload::new(decoded.payload.into_owned(), self.codec.clone());
+
+        Ok(Some(ReceivedMessage {
+            header: decoded.header,
+            payload,
+        }))
+    }
+}
@@ -0,0 +1,131 @@
+//! In-memory transport for testing services
+//!
+//! [`LoopbackClient::connect`] serves a new connection on a [`RiverServer`]
+//! and returns a client for it. No networking is involved, but every message
+//! still goes through the real dispatcher and codec, so tests can assert on
+//! the exact frames a client would see.
+//!
+//! For lower level control, [`loopback`] creates a bare pair of connected
+//! transports.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use super::{CloseReason, Frame, Transport, client::RawClient};
+use crate::{
+    dispatch::{RiverServer, ServiceHandler},
+    types::{HandshakeError, HandshakeResponseOk, RiverResult},
+};
+
+enum LoopbackMessage {
+    Frame(Frame),
+    Close(CloseReason),
+}
+
+/// One end of an in-memory connection
+///
+/// Created in connected pairs by [`loopback`]. Dropping either end
+/// disconnects the other.
+pub struct LoopbackTransport {
+    send: UnboundedSender<LoopbackMessage>,
+    // Unlike kanal's, tokio's receiver is cancel safe
+    recv: UnboundedReceiver<LoopbackMessage>,
+    close_reason: Option<CloseReason>,
+}
+
+/// Creates a pair of connected transports
+pub fn loopback() -> (LoopbackTransport, LoopbackTransport) {
+    let (a_send, b_recv) = unbounded_channel();
+    let (b_send, a_recv) = unbounded_channel();
+
+    (
+        LoopbackTransport {
+            send: a_send,
+            recv: a_recv,
+            close_reason: None,
+        },
+        LoopbackTransport {
+            send: b_send,
+            recv: b_recv,
+            close_reason: None,
+        },
+    )
+}
+
+impl LoopbackTransport {
+    /// The reason the other end closed the connection with, if it has
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+}
+
+impl Transport for LoopbackTransport {
+    async fn recv(&mut self) -> Option<Result<Frame>> {
+        if self.close_reason.is_some() {
+            return None;
+        }
+
+        match self.recv.recv().await? {
+            LoopbackMessage::Frame(frame) => Some(Ok(frame)),
+            LoopbackMessage::Close(reason) => {
+                self.close_reason = Some(reason);
+                None
+            }
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        self.send.send(LoopbackMessage::Frame(frame))?;
+        Ok(())
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> Result<()> {
+        self.send.send(LoopbackMessage::Close(reason))?;
+        Ok(())
+    }
+}
+
+/// A [`RawClient`] connected to a [`RiverServer`] in the same process
+pub type LoopbackClient = RawClient<LoopbackTransport>;
+
+impl LoopbackClient {
+    /// Serves a new connection on `server`, returning a client for it
+    ///
+    /// The connection is served in its own task, so this must be called
+    /// from within a tokio runtime. Messages are encoded with the server's
+    /// default codec.
+    pub fn connect<H: ServiceHandler + 'static>(server: &Arc<RiverServer<H>>) -> Self {
+        Self::new(serve(server), server.codec().clone())
+    }
+
+    /// Serves a new connection on `server` and resumes the session over it
+    ///
+    /// # Errors
+    /// Returns an error if the handshake fails.
+    pub async fn reconnect_to<H: ServiceHandler + 'static>(
+        &mut self,
+        server: &Arc<RiverServer<H>>,
+    ) -> Result<RiverResult<HandshakeResponseOk, HandshakeError>> {
+        self.reconnect(serve(server)).await
+    }
+
+    /// The reason the server closed the connection with, if it has
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.transport().close_reason()
+    }
+}
+
+/// Serves a new connection on `server`, returning the client's end of it
+fn serve<H: ServiceHandler + 'static>(server: &Arc<RiverServer<H>>) -> LoopbackTransport {
+    let (client, transport) = loopback();
+
+    tokio::spawn(
+        server
+            .clone()
+            .serve_transport(transport, "loopback".to_string()),
+    );
+
+    client
+}
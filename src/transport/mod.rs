@@ -0,0 +1,117 @@
+//! Transports that carry River messages between a client and the server
+//!
+//! The dispatcher only needs a way to send and receive whole frames, which
+//! is described by the [`Transport`] trait. WebSockets are served with
+//! [`RiverServer::delta`](crate::dispatch::RiverServer::delta), any other
+//! transport can be served with
+//! [`RiverServer::serve_transport`](crate::dispatch::RiverServer::serve_transport).
+//!
+//! # Provided transports
+//! - [`loopback`]: in-memory connections for testing services
+//! - [`tcp`]: length-prefixed frames over raw TCP
+//! - [`unix`]: length-prefixed frames over Unix domain sockets
+//! - [`stdio`]: length-prefixed frames over a process's stdin and stdout
+//!
+//! [`client::RawClient`] can talk to a server over any of them.
+
+pub mod client;
+pub mod loopback;
+pub mod stdio;
+pub mod stream;
+pub mod tcp;
+#[cfg(unix)]
+pub mod unix;
+pub(crate) mod websocket;
+
+use std::future::Future;
+
+use anyhow::Result;
+use axum::body::Bytes;
+
+/// A single encoded River message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The encoded message
+    pub data: Bytes,
+    /// Whether the message should be sent as text
+    ///
+    /// Only meaningful for transports that distinguish text and binary
+    /// frames (like WebSockets), others can ignore it.
+    pub text: bool,
+}
+
+impl Frame {
+    /// Creates a binary frame
+    pub fn binary(data: impl Into<Bytes>) -> Self {
+        Self {
+            data: data.into(),
+            text: false,
+        }
+    }
+
+    /// Creates a text frame
+    pub fn text(data: impl Into<Bytes>) -> Self {
+        Self {
+            data: data.into(),
+            text: true,
+        }
+    }
+}
+
+/// Why the server is closing a connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// No specific reason, like when a client misbehaves during the handshake
+    Normal,
+    /// A frame was larger than the server allows
+    FrameTooLarge,
+    /// The client sent text frames to a codec that can't read them
+    UnsupportedFrame,
+    /// The client exceeded its rate limit
+    RateLimited,
+    /// The client stopped answering heartbeats
+    Unresponsive,
+    /// The client didn't handshake in time
+    HandshakeTimeout,
+    /// The client sent something other than a valid handshake request
+    ProtocolError,
+    /// The client left too many messages unacknowledged
+    SessionBufferFull,
+}
+
+impl CloseReason {
+    /// A short description of the reason, suitable for sending to the client
+    pub fn description(self) -> &'static str {
+        match self {
+            CloseReason::Normal => "",
+            CloseReason::FrameTooLarge => "Frame too large",
+            CloseReason::UnsupportedFrame => "Text frames are not supported by this codec",
+            CloseReason::RateLimited => "Rate limit exceeded",
+            CloseReason::Unresponsive => "Missed too many heartbeats",
+            CloseReason::HandshakeTimeout => "Handshake timed out",
+            CloseReason::ProtocolError => "Invalid handshake",
+            CloseReason::SessionBufferFull => "Too many unacknowledged messages",
+        }
+    }
+}
+
+/// A connection to a single client
+///
+/// Implementations must be cancel safe in [`Transport::recv`], as the
+/// dispatcher receives frames and sends responses concurrently.
+pub trait Transport: Send + 'static {
+    /// Receives the next frame, returning `None` once the client disconnects
+    fn recv(&mut self) -> impl Future<Output = Option<Result<Frame>>> + Send;
+
+    /// Sends a frame to the client
+    ///
+    /// # Errors
+    /// Returns an error if the connection has failed.
+    fn send(&mut self, frame: Frame) -> impl Future<Output = Result<()>> + Send;
+
+    /// Closes the connection
+    ///
+    /// # Errors
+    /// Returns an error if the connection has already failed.
+    fn close(&mut self, reason: CloseReason) -> impl Future<Output = Result<()>> + Send;
+}
@@ -0,0 +1,98 @@
+//! Stdio transport for subprocess plugins
+//!
+//! Runs River over a process's stdin and stdout, with the same
+//! [length-prefixed](super::stream) framing as the other stream transports.
+//!
+//! - A plugin serves its procedures to its host with [`RiverServer::serve_stdio`].
+//! - A host spawns a plugin with [`ChildTransport::spawn`], and calls its
+//!   procedures with a [`RawClient`](super::client::RawClient).
+//!
+//! As stdout carries the protocol, plugins must not print anything to it.
+//! Logs should be written to stderr instead.
+
+use std::sync::Arc;
+
+use anyhow::{Result, format_err};
+use tokio::{
+    io::{Stdin, Stdout},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+use super::{CloseReason, Frame, Transport, stream::StreamTransport};
+use crate::dispatch::{RiverServer, ServiceHandler};
+
+/// A [`StreamTransport`] over this process's stdin and stdout
+pub type StdioTransport = StreamTransport<Stdin, Stdout>;
+
+impl StdioTransport {
+    /// Creates a transport over this process's stdin and stdout
+    pub fn stdio() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+}
+
+impl<H: ServiceHandler + 'static> RiverServer<H> {
+    /// Serves a single client, the host process, over stdin and stdout
+    ///
+    /// Resolves once the host closes stdin.
+    pub async fn serve_stdio(self: Arc<Self>) {
+        let transport = StdioTransport::stdio().with_max_frame_size(self.stream_max_frame_size());
+
+        self.serve_transport(transport, "stdio".to_string()).await;
+    }
+}
+
+/// A transport over the stdin and stdout of a child process
+///
+/// The child is killed when the transport is dropped.
+pub struct ChildTransport {
+    inner: StreamTransport<ChildStdout, ChildStdin>,
+    child: Child,
+}
+
+impl ChildTransport {
+    /// Spawns `command` with piped stdin and stdout, and connects to it
+    ///
+    /// # Errors
+    /// Returns an error if the process can't be spawned.
+    pub fn spawn(command: &mut Command) -> Result<Self> {
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| format_err!("Child stdin was not piped"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| format_err!("Child stdout was not piped"))?;
+
+        Ok(Self {
+            inner: StreamTransport::new(stdout, stdin),
+            child,
+        })
+    }
+
+    /// The child process
+    pub fn child(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Transport for ChildTransport {
+    async fn recv(&mut self) -> Option<Result<Frame>> {
+        self.inner.recv().await
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        self.inner.send(frame).await
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> Result<()> {
+        self.inner.close(reason).await
+    }
+}
@@ -0,0 +1,119 @@
+//! Length-prefixed framing for byte stream transports
+//!
+//! Every frame is sent as a 4 byte big-endian length followed by that many
+//! bytes of codec output. There is no distinction between text and binary
+//! frames, so every frame is received as binary.
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{CloseReason, Frame, Transport};
+
+/// Size of the length prefix in front of every frame
+const LENGTH_PREFIX: usize = 4;
+
+/// The largest frame received unless configured otherwise, 16 MiB
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// A [`Transport`] over any pair of byte streams
+///
+/// Used by the TCP, Unix socket, and stdio transports. Frames larger than
+/// the [maximum frame size](Self::with_max_frame_size) are rejected before
+/// they are buffered.
+pub struct StreamTransport<R, W> {
+    reader: R,
+    writer: W,
+    buffer: BytesMut,
+    max_frame_size: usize,
+}
+
+impl<R, W> StreamTransport<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Creates a transport that reads frames from `reader` and writes them to `writer`
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader,
+            writer,
+            buffer: BytesMut::new(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Sets the largest frame that will be received, in bytes
+    ///
+    /// Defaults to [`DEFAULT_MAX_FRAME_SIZE`], so a corrupt or hostile length
+    /// prefix can't make the transport buffer up to 4 GiB.
+    /// Receiving a larger frame fails the connection. This is checked against
+    /// the length prefix, unlike
+    /// [`RiverServerBuilder::max_frame_size`](crate::dispatch::RiverServerBuilder::max_frame_size)
+    /// which only sees whole frames.
+    #[must_use]
+    pub fn with_max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Returns the length of the frame at the start of the buffer, if its prefix has arrived
+    fn frame_len(&self) -> Option<usize> {
+        let prefix = self.buffer.get(..LENGTH_PREFIX)?;
+
+        Some(u32::from_be_bytes(prefix.try_into().unwrap()) as usize)
+    }
+}
+
+impl<R, W> Transport for StreamTransport<R, W>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    async fn recv(&mut self) -> Option<Result<Frame>> {
+        // Partial frames stay in the buffer, which keeps this cancel safe
+        loop {
+            if let Some(len) = self.frame_len() {
+                if len > self.max_frame_size {
+                    return Some(Err(anyhow::format_err!(
+                        "Frame of {len} bytes exceeds the maximum of {}",
+                        self.max_frame_size
+                    )));
+                }
+
+                if self.buffer.len() >= LENGTH_PREFIX + len {
+                    self.buffer.advance(LENGTH_PREFIX);
+                    let data = self.buffer.split_to(len).freeze();
+
+                    return Some(Ok(Frame::binary(data)));
+                }
+            }
+
+            match self.reader.read_buf(&mut self.buffer).await {
+                Ok(0) if self.buffer.is_empty() => return None,
+                Ok(0) => return Some(Err(anyhow::format_err!("Connection closed mid-frame"))),
+                Ok(_) => {}
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        let Ok(len) = u32::try_from(frame.data.len()) else {
+            bail!("Frame of {} bytes is too large to send", frame.data.len());
+        };
+
+        // Written together so small frames go out in a single packet
+        let prefix = len.to_be_bytes();
+        let mut buf = Buf::chain(&prefix[..], frame.data);
+        self.writer.write_all_buf(&mut buf).await?;
+        self.writer.flush().await?;
+
+        Ok(())
+    }
+
+    async fn close(&mut self, _reason: CloseReason) -> Result<()> {
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+}
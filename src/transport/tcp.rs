@@ -0,0 +1,71 @@
+//! Raw TCP transport
+//!
+//! Frames are [length-prefixed](super::stream) and carry the same codec
+//! output as WebSocket frames, without the overhead of an HTTP upgrade.
+//! Serve connections with [`RiverServer::serve_tcp`], and connect to a
+//! server with [`TcpTransport::connect`].
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::net::{
+    TcpListener, TcpStream, ToSocketAddrs,
+    tcp::{OwnedReadHalf, OwnedWriteHalf},
+};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use super::stream::StreamTransport;
+use crate::dispatch::{RiverServer, ServiceHandler};
+
+/// A [`StreamTransport`] over a TCP connection
+pub type TcpTransport = StreamTransport<OwnedReadHalf, OwnedWriteHalf>;
+
+impl TcpTransport {
+    /// Creates a transport over an established connection
+    ///
+    /// Nagle's algorithm is disabled, as River messages are usually small
+    /// and latency sensitive.
+    ///
+    /// # Errors
+    /// Returns an error if `TCP_NODELAY` can't be set.
+    pub fn from_stream(stream: TcpStream) -> Result<Self> {
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+
+        Ok(Self::new(reader, writer))
+    }
+
+    /// Connects to a River server listening on `addr`
+    ///
+    /// # Errors
+    /// Returns an error if the connection can't be established.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::from_stream(TcpStream::connect(addr).await?)
+    }
+}
+
+impl<H: ServiceHandler + 'static> RiverServer<H> {
+    /// Serves every client that connects to `listener`
+    ///
+    /// Each connection is served in its own task. This only returns if
+    /// accepting connections fails.
+    ///
+    /// # Errors
+    /// Returns an error if the listener stops accepting connections.
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+
+            let transport = match TcpTransport::from_stream(stream) {
+                Ok(transport) => transport.with_max_frame_size(self.stream_max_frame_size()),
+                Err(err) => {
+                    warn!(%addr, %err, "Failed to set up TCP connection");
+                    continue;
+                }
+            };
+
+            tokio::spawn(self.clone().serve_transport(transport, addr.to_string()));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Unix domain socket transport
+//!
+//! Frames are [length-prefixed](super::stream) the same way as the
+//! [TCP transport](super::tcp). Serve connections with
+//! [`RiverServer::serve_unix`], and connect to a server with
+//! [`UnixTransport::connect`].
+//!
+//! # Access control
+//! Connecting to a Unix socket requires write permission on the socket
+//! file, so access can be restricted with regular filesystem permissions on
+//! the socket or its parent directory. Connections are logged with the
+//! user and process id of the client.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::Result;
+use tokio::net::{
+    UnixListener, UnixStream,
+    unix::{OwnedReadHalf, OwnedWriteHalf},
+};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use super::stream::StreamTransport;
+use crate::dispatch::{RiverServer, ServiceHandler};
+
+/// A [`StreamTransport`] over a Unix domain socket
+pub type UnixTransport = StreamTransport<OwnedReadHalf, OwnedWriteHalf>;
+
+impl UnixTransport {
+    /// Creates a transport over an established connection
+    pub fn from_stream(stream: UnixStream) -> Self {
+        let (reader, writer) = stream.into_split();
+
+        Self::new(reader, writer)
+    }
+
+    /// Connects to a River server listening on the socket at `path`
+    ///
+    /// # Errors
+    /// Returns an error if the connection can't be established.
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::from_stream(UnixStream::connect(path).await?))
+    }
+}
+
+impl<H: ServiceHandler + 'static> RiverServer<H> {
+    /// Serves every client that connects to `listener`
+    ///
+    /// Each connection is served in its own task. This only returns if
+    /// accepting connections fails.
+    ///
+    /// # Errors
+    /// Returns an error if the listener stops accepting connections.
+    pub async fn serve_unix(self: Arc<Self>, listener: UnixListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let peer = describe_peer(&stream);
+            let transport = UnixTransport::from_stream(stream)
+                .with_max_frame_size(self.stream_max_frame_size());
+
+            tokio::spawn(self.clone().serve_transport(transport, peer));
+        }
+    }
+}
+
+/// Describes a client by its credentials, as Unix socket clients rarely have an address
+fn describe_peer(stream: &UnixStream) -> String {
+    match stream.peer_cred() {
+        Ok(cred) => match cred.pid() {
+            Some(pid) => format!("unix:uid={},pid={pid}", cred.uid()),
+            None => format!("unix:uid={}", cred.uid()),
+        },
+        Err(_) => "unix".to_string(),
+    }
+}
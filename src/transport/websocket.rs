@@ -0,0 +1,96 @@
+//! [`Transport`] implementation for axum WebSockets
+
+use anyhow::Result;
+use axum::{
+    body::Bytes,
+    extract::ws::{CloseFrame, Message as WsMessage, WebSocket, close_code},
+};
+#[allow(unused_imports)]
+use tracing::{debug, error, info, trace, warn};
+
+use super::{CloseReason, Frame, Transport};
+
+/// A client connected over a WebSocket
+pub(crate) struct WebSocketTransport {
+    socket: WebSocket,
+}
+
+impl WebSocketTransport {
+    pub(crate) fn new(socket: WebSocket) -> Self {
+        Self { socket }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    async fn recv(&mut self) -> Option<Result<Frame>> {
+        loop {
+            let message = match self.socket.recv().await? {
+                Ok(message) => message,
+                Err(err) => {
+                    let error_message = err.to_string();
+
+                    if error_message.contains("Connection reset without closing handshake") {
+                        warn!("Client connection reset without closing handshake");
+                        return None;
+                    }
+
+                    // Messages over the server's max frame size are refused before they are buffered
+                    if error_message.contains("Space limit exceeded") {
+                        warn!("Frame too large, disconnecting client");
+                        self.close(CloseReason::FrameTooLarge).await.ok();
+                    }
+
+                    return Some(Err(err.into()));
+                }
+            };
+
+            match message {
+                WsMessage::Binary(data) => return Some(Ok(Frame::binary(data))),
+                WsMessage::Text(text) => return Some(Ok(Frame::text(text))),
+                WsMessage::Close(_) => return None,
+                // Pings are answered by axum
+                WsMessage::Ping(_) | WsMessage::Pong(_) => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<()> {
+        self.socket.send(ws_message(frame)).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> Result<()> {
+        let code = match reason {
+            CloseReason::Normal => None,
+            CloseReason::FrameTooLarge => Some(close_code::SIZE),
+            CloseReason::UnsupportedFrame => Some(close_code::UNSUPPORTED),
+            CloseReason::RateLimited
+            | CloseReason::HandshakeTimeout
+            | CloseReason::SessionBufferFull => Some(close_code::POLICY),
+            CloseReason::Unresponsive => Some(close_code::AWAY),
+            CloseReason::ProtocolError => Some(close_code::PROTOCOL),
+        };
+
+        let frame = code.map(|code| CloseFrame {
+            code,
+            reason: reason.description().into(),
+        });
+
+        self.socket.send(WsMessage::Close(frame)).await?;
+        Ok(())
+    }
+}
+
+/// Wraps a frame in a WebSocket message
+///
+/// Text frames fall back to binary messages if they are not valid UTF-8.
+fn ws_message(frame: Frame) -> WsMessage {
+    if !frame.text {
+        return WsMessage::Binary(frame.data);
+    }
+
+    match String::from_utf8(frame.data.into()) {
+        Ok(text) => WsMessage::Text(text.into()),
+        Err(err) => WsMessage::Binary(Bytes::from_owner(err.into_bytes())),
+    }
+}
@@ -1,22 +1,224 @@
 //! ## What does a codec do?
 //! Codecs are used to transform messages into and from their
 //! over the wire representation.
+//!
+//! ## Which trait should I use?
+//! Codecs are implemented using [`Codec`], which can encode and
+//! decode any serde type. Because its methods are generic it can't
+//! be used as a trait object, so anything that needs to pick a codec
+//! at runtime uses [`TransportCodec`] instead. Every [`Codec`]
+//! implements [`TransportCodec`], so `Arc<dyn TransportCodec>` can
+//! hold built-in and third party codecs alike.
+//!
+//! ## Payloads
+//! The dispatcher decodes each message with [`Codec::decode_frame`],
+//! which leaves the payload encoded. Procedures receive it as a
+//! [`RawPayload`](super::RawPayload) and decode it straight into their
+//! own types, so payloads are only ever parsed once.
+
+use std::{borrow::Cow, marker::PhantomData, sync::Arc};
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{DeserializeSeed, Error as _},
+};
+
+use super::{RawFrame, RequestInner, TransportControlMessage, TransportRequestMessage};
+
+/// Callback used by [`TransportCodec::decode_payload`] to deserialize a payload
+pub type PayloadVisitor<'f, 'a> =
+    dyn FnMut(&mut dyn erased_serde::Deserializer<'a>) -> Result<(), erased_serde::Error> + 'f;
 
 /// A trait that represents a codec
 ///
 /// Codecs are used to transform messages into and from their
 /// over the wire representation.
-pub trait Codec: Send + Sync + Copy {
+pub trait Codec: Send + Sync {
+    /// Decode a slice using a [`DeserializeSeed`]
+    ///
+    /// # Errors
+    /// Returns an error if `v` is not a valid encoding of the seed's value.
+    fn decode_seed<'a, S>(&self, v: &'a [u8], seed: S) -> Result<S::Value>
+    where
+        S: DeserializeSeed<'a>;
+
     /// Decode a slice into a value
+    ///
+    /// # Errors
+    /// Returns an error if `v` is not a valid encoding of `T`.
     fn decode_slice<'a, T>(&self, v: &'a [u8]) -> Result<T>
     where
-        T: Deserialize<'a>;
+        T: Deserialize<'a>,
+    {
+        self.decode_seed(v, PhantomData)
+    }
+
+    /// Decode a message, leaving its payload encoded
+    ///
+    /// The default implementation decodes the payload and encodes it
+    /// again, codecs that can find the payload's bytes in `v` should
+    /// override this to borrow them instead.
+    ///
+    /// # Errors
+    /// Returns an error if `v` is not a valid River message.
+    fn decode_frame<'a>(&self, v: &'a [u8]) -> Result<RawFrame<'a>> {
+        let message: TransportRequestMessage = self.decode_slice(v)?;
+
+        let (service_name, procedure_name, payload) = match message.inner {
+            RequestInner::Init {
+                service_name,
+                procedure_name,
+                payload,
+            } => (Some(service_name), Some(procedure_name), payload),
+            RequestInner::Request { payload } => (None, None, payload),
+        };
+
+        Ok(RawFrame {
+            header: message.header,
+            service_name,
+            procedure_name,
+            payload: Cow::Owned(self.encode_to_vec(&payload)?),
+        })
+    }
 
     /// Encode a value into a vector
+    ///
+    /// # Errors
+    /// Returns an error if `value` cannot be represented by this codec.
     fn encode_to_vec<T>(&self, value: &T) -> Result<Vec<u8>>
     where
         T: ?Sized + Serialize;
+
+    /// Whether encoded messages are always valid UTF-8
+    ///
+    /// Connections using a text codec accept WebSocket text frames as well
+    /// as binary frames.
+    fn is_text(&self) -> bool {
+        false
+    }
+}
+
+/// Object safe view of a [`Codec`] that only deals with River messages
+///
+/// This trait is implemented for every [`Codec`] and should not need
+/// to be implemented manually. See the [module level documentation](self)
+/// for why it exists.
+pub trait TransportCodec: Send + Sync {
+    /// Decode a message, leaving its payload encoded
+    ///
+    /// # Errors
+    /// Returns an error if `v` is not a valid River message.
+    fn decode_frame<'a>(&self, v: &'a [u8]) -> Result<RawFrame<'a>>;
+
+    /// Decode a payload by handing a deserializer for `v` to `visit`
+    ///
+    /// This is what [`RawPayload::decode`](super::RawPayload::decode) uses
+    /// to decode into arbitrary types without knowing the codec.
+    ///
+    /// # Errors
+    /// Returns an error if `v` is not valid for this codec, or if `visit` fails.
+    fn decode_payload<'a>(&self, v: &'a [u8], visit: &mut PayloadVisitor<'_, 'a>) -> Result<()>;
+
+    /// Decode a control message
+    ///
+    /// # Errors
+    /// Returns an error if `v` is not a valid control message.
+    fn decode_control(&self, v: &[u8]) -> Result<TransportControlMessage>;
+
+    /// Decode a procedure message
+    ///
+    /// # Errors
+    /// Returns an error if `v` is not a valid procedure message.
+    fn decode_request(&self, v: &[u8]) -> Result<TransportRequestMessage>;
+
+    /// Encode a control message
+    ///
+    /// # Errors
+    /// Returns an error if the message cannot be represented by this codec.
+    fn encode_control(&self, message: &TransportControlMessage) -> Result<Vec<u8>>;
+
+    /// Encode a procedure message
+    ///
+    /// # Errors
+    /// Returns an error if the message cannot be represented by this codec.
+    fn encode_request(&self, message: &TransportRequestMessage) -> Result<Vec<u8>>;
+
+    /// Whether encoded messages are always valid UTF-8, see [`Codec::is_text`]
+    fn is_text(&self) -> bool;
+}
+
+impl<C: Codec> TransportCodec for C {
+    fn decode_frame<'a>(&self, v: &'a [u8]) -> Result<RawFrame<'a>> {
+        Codec::decode_frame(self, v)
+    }
+
+    fn decode_payload<'a>(&self, v: &'a [u8], visit: &mut PayloadVisitor<'_, 'a>) -> Result<()> {
+        self.decode_seed(v, ErasedSeed(visit))
+    }
+
+    fn decode_control(&self, v: &[u8]) -> Result<TransportControlMessage> {
+        self.decode_slice(v)
+    }
+
+    fn decode_request(&self, v: &[u8]) -> Result<TransportRequestMessage> {
+        self.decode_slice(v)
+    }
+
+    fn encode_control(&self, message: &TransportControlMessage) -> Result<Vec<u8>> {
+        self.encode_to_vec(message)
+    }
+
+    fn encode_request(&self, message: &TransportRequestMessage) -> Result<Vec<u8>> {
+        self.encode_to_vec(message)
+    }
+
+    fn is_text(&self) -> bool {
+        Codec::is_text(self)
+    }
+}
+
+impl<T: TransportCodec + ?Sized> TransportCodec for Arc<T> {
+    fn decode_frame<'a>(&self, v: &'a [u8]) -> Result<RawFrame<'a>> {
+        (**self).decode_frame(v)
+    }
+
+    fn decode_payload<'a>(&self, v: &'a [u8], visit: &mut PayloadVisitor<'_, 'a>) -> Result<()> {
+        (**self).decode_payload(v, visit)
+    }
+
+    fn decode_control(&self, v: &[u8]) -> Result<TransportControlMessage> {
+        (**self).decode_control(v)
+    }
+
+    fn decode_request(&self, v: &[u8]) -> Result<TransportRequestMessage> {
+        (**self).decode_request(v)
+    }
+
+    fn encode_control(&self, message: &TransportControlMessage) -> Result<Vec<u8>> {
+        (**self).encode_control(message)
+    }
+
+    fn encode_request(&self, message: &TransportRequestMessage) -> Result<Vec<u8>> {
+        (**self).encode_request(message)
+    }
+
+    fn is_text(&self) -> bool {
+        (**self).is_text()
+    }
+}
+
+/// Seed that hands a type erased version of its deserializer to a [`PayloadVisitor`]
+struct ErasedSeed<'v, 'f, 'a>(&'v mut PayloadVisitor<'f, 'a>);
+
+impl<'a> DeserializeSeed<'a> for ErasedSeed<'_, '_, 'a> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'a>,
+    {
+        let mut erased = <dyn erased_serde::Deserializer>::erase(deserializer);
+        (self.0)(&mut erased).map_err(D::Error::custom)
+    }
 }
@@ -38,11 +38,15 @@ pub struct HandshakeRequest {
     /// Connection session, used for reconnects
     pub session_id: String,
     /// Metadata used for transparent reconnects
+    ///
+    /// v1.1 clients may leave this out, in which case it is zeroed.
+    #[serde(default)]
     pub expected_session_state: ExpectedSessionState,
     /// Optional metadata sent from the client
     ///
-    /// As Rapids does not yet support custom handlers/middleware on connection,
-    /// metadata passed in the handshake by a client is currently unreadable.
+    /// Can be validated with
+    /// [`RiverServerBuilder::on_handshake`](crate::dispatch::RiverServerBuilder::on_handshake).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub metadata: Option<serde_json::Value>, // TODO: metadata as generic?
 }
 
@@ -64,6 +68,12 @@ pub struct HandshakeResponse {
 pub struct HandshakeResponseOk {
     /// The accepted `session_id`
     pub session_id: String,
+    /// Identifies the server instance that owns the session
+    ///
+    /// Only sent if the server sets one, see
+    /// [`RiverServerBuilder::affinity_token`](crate::dispatch::RiverServerBuilder::affinity_token).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub affinity_token: Option<String>,
 }
 
 /// Errors that the server can alert the client of when a handshake fails.
@@ -126,12 +136,13 @@ pub enum ProtocolVersion {
     /// Unsupported
     #[serde(rename = "v1")]
     V1,
-    /// # v1.0
-    /// Unsupported
+    /// # v1.1
+    /// Supported when enabled with
+    /// [`RiverServerBuilder::v1_1_compatibility`](crate::dispatch::RiverServerBuilder::v1_1_compatibility)
     #[serde(rename = "v1.1")]
     V1_1,
     /// # v2.0
-    /// The only version this library currently supports
+    /// The version this library implements
     #[serde(rename = "v2.0")]
     V2_0,
     /// # Unknown version
@@ -155,12 +166,17 @@ impl Display for ProtocolVersion {
 
 /// Session state used for transparent reconnects
 ///
-/// NOTE: ⚠️ Transparent reconnects are not supported by the built in dispatcher/server. ⚠️
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+/// The built in dispatcher only resumes sessions if they are configured to be
+/// resumable, see [`sessions`](crate::sessions).
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct ExpectedSessionState {
     /// The next `seq` that the client expects from the server.
+    #[serde(default)]
     pub next_expected_seq: i64,
     /// The next `ack` that the client expects from the server.
+    ///
+    /// Not sent by v1.1 clients, which send whether they are reconnecting instead.
+    #[serde(default)]
     pub next_sent_seq: i64,
 }
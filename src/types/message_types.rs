@@ -19,8 +19,11 @@
 //! [`TransportRequestMessage`]. It is also the type sent
 //! from `procedure -> dispatch`
 
+use std::borrow::Cow;
+
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+
+use super::Payload;
 
 /// Shared header information that all messages have
 #[derive(Deserialize, Serialize, Clone, Debug)]
@@ -35,20 +38,18 @@ pub struct Header {
     pub control_flags: i32,
 }
 
-/// Minimal representation of [`Header`] required for the dispatcher
+/// Any message, decoded without its payload
 ///
-/// The dispatcher uses this information to figure out which
-/// `TransportMessage` struct it should use when decoding a
-/// message.
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct HeaderID {
-    pub stream_id: String,
+/// Produced by [`Codec::decode_frame`](super::Codec::decode_frame), this is
+/// how the dispatcher reads incoming messages. `service_name` and
+/// `procedure_name` are only set for `Init` messages.
+#[derive(Clone, Debug)]
+pub struct RawFrame<'a> {
+    pub header: Header,
     pub service_name: Option<String>,
     pub procedure_name: Option<String>,
-    pub control_flags: i32,
-    pub seq: i32,
-    pub ack: i32,
+    /// The payload, still encoded by the codec that decoded this frame
+    pub payload: Cow<'a, [u8]>,
 }
 
 /// Generic transport message
@@ -99,8 +100,8 @@ pub enum RequestInner {
     Init {
         service_name: String,
         procedure_name: String,
-        payload: Value,
+        payload: Payload,
     },
     /// Used to sent updates/responses to an ongoing procedure
-    Request { payload: Value },
+    Request { payload: Payload },
 }
@@ -1,13 +1,36 @@
 //! Miscellaneous types used within Rapids
 
+use std::fmt::Display;
+
+use anyhow::format_err;
 use kanal::AsyncSender;
+use tokio::task::AbortHandle;
 
-use crate::types::RequestInner;
+use crate::types::{Payload, RawPayload, RequestInner};
 
 /// Used by the dispatcher to associate a `stream_id` with the needed metadata
 pub struct StreamInfo {
     /// Channel to communicate with ongoing the procedure task
     pub messenger: AsyncSender<IncomingMessage>,
+    /// Largest message, in bytes, the procedure accepts
+    pub max_payload_size: Option<usize>,
+    /// Aborts the procedure task
+    pub task: AbortHandle,
+}
+
+impl StreamInfo {
+    /// Tells the procedure that it has to stop
+    ///
+    /// The dispatcher never waits on a procedure, so procedures that aren't
+    /// reading their messages are aborted instead.
+    pub fn force_close(self) {
+        if !matches!(
+            self.messenger.try_send(IncomingMessage::ForceClose),
+            Ok(true)
+        ) {
+            self.task.abort();
+        }
+    }
 }
 
 /// Sent from `dispatcher -> multi-message procedures`
@@ -17,10 +40,11 @@ pub enum IncomingMessage {
     /// The client has disconnected
     ForceClose,
     /// The client has sent a new message
-    Request(serde_json::Value),
+    Request(RawPayload),
 }
 
 /// General information needed by procedure handlers
+#[derive(Clone)]
 pub struct RPCMetadata {
     /// The `stream_id` of the invoked procedure
     pub stream_id: String,
@@ -58,5 +82,49 @@ pub enum ProcedureRes {
     /// The procedure is sending a response message
     ///
     /// This is used by `rpc`/`upload`
-    Response(serde_json::Value),
+    Response(Payload),
+}
+
+/// Error codes River reserves for procedures that fail outside of user code.
+///
+/// These are sent in the `code` field of an error result, see
+/// [`utils::error_payload`](crate::utils::error_payload).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcedureError {
+    /// The procedure threw/returned an error that was not handled
+    UncaughtError,
+    /// The connection was lost while the procedure was running
+    UnexpectedDisconnect,
+    /// The request was invalid or could not be accepted
+    InvalidRequest,
+    /// The procedure was cancelled, either by the client or the server
+    Cancel,
+}
+
+impl Display for ProcedureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let to_write = match self {
+            ProcedureError::UncaughtError => "UNCAUGHT_ERROR",
+            ProcedureError::UnexpectedDisconnect => "UNEXPECTED_DISCONNECT",
+            ProcedureError::InvalidRequest => "INVALID_REQUEST",
+            ProcedureError::Cancel => "CANCEL",
+        };
+
+        f.write_str(to_write)
+    }
+}
+
+impl TryFrom<String> for ProcedureError {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value: &str = &value;
+        match value {
+            "UNCAUGHT_ERROR" => Ok(ProcedureError::UncaughtError),
+            "UNEXPECTED_DISCONNECT" => Ok(ProcedureError::UnexpectedDisconnect),
+            "INVALID_REQUEST" => Ok(ProcedureError::InvalidRequest),
+            "CANCEL" => Ok(ProcedureError::Cancel),
+            _ => Err(format_err!("Unknown ProcedureError: `{value}`")),
+        }
+    }
 }
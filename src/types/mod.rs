@@ -17,10 +17,12 @@ pub mod codecs;
 pub mod control;
 pub mod message_types;
 pub mod misc;
+pub mod payload;
 pub mod result;
 
 pub use codecs::*;
 pub use control::*;
 pub use message_types::*;
 pub use misc::*;
+pub use payload::*;
 pub use result::*;
@@ -0,0 +1,196 @@
+//! Procedure payloads
+//!
+//! Incoming payloads are handed to procedures as a [`RawPayload`], still
+//! encoded by the connection's codec. Outgoing payloads are built as a
+//! [`Payload`], which can hold any value a codec can represent, including
+//! raw bytes. Codecs with a native binary type, like MessagePack and CBOR,
+//! send bytes as-is, while JSON falls back to an array of numbers.
+
+use std::{fmt::Display, sync::Arc};
+
+use anyhow::{Result, format_err};
+use axum::body::Bytes;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::types::TransportCodec;
+
+/// An outgoing payload
+///
+/// Unlike [`serde_json::Value`], a payload can contain binary data, which
+/// is how procedures send files and other blobs efficiently. Use
+/// [`Payload::new`] to build one from any serializable type, or convert
+/// an existing [`serde_json::Value`] with [`From`].
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Payload(rmpv::Value);
+
+impl Payload {
+    /// Serializes `value` into a payload
+    ///
+    /// Byte buffers serialized with `serialize_bytes`, such as
+    /// [`serde_bytes::ByteBuf`](https://docs.rs/serde_bytes), are kept as binary data.
+    ///
+    /// # Errors
+    /// Returns an error if `value` fails to serialize.
+    pub fn new<T>(value: &T) -> Result<Self>
+    where
+        T: ?Sized + Serialize,
+    {
+        // Encoded with field names, as `rmpv::ext` would turn structs into arrays
+        let data = rmp_serde::to_vec_named(value)?;
+
+        Ok(Self(rmpv::decode::read_value(&mut data.as_slice())?))
+    }
+
+    /// Creates a payload containing binary data
+    pub fn bytes(bytes: impl Into<Vec<u8>>) -> Self {
+        Self(rmpv::Value::Binary(bytes.into()))
+    }
+
+    /// Creates a `null` payload
+    pub fn null() -> Self {
+        Self(rmpv::Value::Nil)
+    }
+
+    /// Deserializes the payload into `T`
+    ///
+    /// # Errors
+    /// Returns an error if the payload is not a valid `T`.
+    pub fn decode<T: DeserializeOwned>(self) -> Result<T> {
+        let mut data = Vec::new();
+        rmpv::encode::write_value(&mut data, &self.0)?;
+
+        Ok(rmp_serde::from_slice(&data)?)
+    }
+
+    /// The underlying value
+    pub fn as_value(&self) -> &rmpv::Value {
+        &self.0
+    }
+
+    /// Builds a map payload from string keys
+    pub(crate) fn map<const N: usize>(entries: [(&str, Payload); N]) -> Self {
+        Self(rmpv::Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.0))
+                .collect(),
+        ))
+    }
+}
+
+impl Display for Payload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<rmpv::Value> for Payload {
+    fn from(value: rmpv::Value) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Payload> for rmpv::Value {
+    fn from(payload: Payload) -> Self {
+        payload.0
+    }
+}
+
+impl From<serde_json::Value> for Payload {
+    fn from(value: serde_json::Value) -> Self {
+        fn convert(value: serde_json::Value) -> rmpv::Value {
+            match value {
+                serde_json::Value::Null => rmpv::Value::Nil,
+                serde_json::Value::Bool(value) => value.into(),
+                serde_json::Value::Number(number) => {
+                    if let Some(number) = number.as_u64() {
+                        number.into()
+                    } else if let Some(number) = number.as_i64() {
+                        number.into()
+                    } else {
+                        number.as_f64().map_or(rmpv::Value::Nil, rmpv::Value::F64)
+                    }
+                }
+                serde_json::Value::String(value) => value.into(),
+                serde_json::Value::Array(values) => {
+                    rmpv::Value::Array(values.into_iter().map(convert).collect())
+                }
+                serde_json::Value::Object(entries) => rmpv::Value::Map(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (key.into(), convert(value)))
+                        .collect(),
+                ),
+            }
+        }
+
+        Self(convert(value))
+    }
+}
+
+impl From<bool> for Payload {
+    fn from(value: bool) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<&str> for Payload {
+    fn from(value: &str) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<String> for Payload {
+    fn from(value: String) -> Self {
+        Self(value.into())
+    }
+}
+
+/// A payload that has not been decoded yet
+///
+/// Payloads are kept in the encoding they were received in, so procedures
+/// can decode them directly into their own types with [`decode`](Self::decode).
+/// Cloning is cheap, the underlying buffer is shared.
+#[derive(Clone)]
+pub struct RawPayload {
+    bytes: Bytes,
+    codec: Arc<dyn TransportCodec>,
+}
+
+impl RawPayload {
+    /// Wraps `bytes` that were encoded with `codec`
+    pub fn new(bytes: impl Into<Bytes>, codec: Arc<dyn TransportCodec>) -> Self {
+        Self {
+            bytes: bytes.into(),
+            codec,
+        }
+    }
+
+    /// Decodes the payload into `T`
+    ///
+    /// `T` may borrow from the payload, for example `&str` fields are
+    /// not copied when the codec allows it.
+    ///
+    /// # Errors
+    /// Returns an error if the payload is not a valid `T`.
+    pub fn decode<'a, T>(&'a self) -> Result<T>
+    where
+        T: Deserialize<'a>,
+    {
+        let mut value = None;
+
+        self.codec
+            .decode_payload(&self.bytes, &mut |deserializer| {
+                value = Some(erased_serde::deserialize(deserializer)?);
+                Ok(())
+            })?;
+
+        value.ok_or_else(|| format_err!("Codec did not decode the payload"))
+    }
+
+    /// The encoded payload
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
@@ -4,7 +4,8 @@ use nanoid::nanoid;
 use tracing::debug;
 
 use crate::types::{
-    Control, OutgoingMessage, ProcedureRes, RPCMetadata, RequestInner, SimpleOutgoingMessage,
+    Control, OutgoingMessage, Payload, ProcedureRes, RPCMetadata, RequestInner,
+    SimpleOutgoingMessage,
 };
 
 /// Alphanumeric alphabet used by [`generate_id`]
@@ -24,6 +25,31 @@ pub fn generate_id() -> String {
     nanoid!(12, &NANOID_ALPHABET)
 }
 
+/// Builds the payload of a successful procedure result
+///
+/// River sends results as `{ "ok": true, "payload": payload }`.
+pub fn ok_payload(payload: impl Into<Payload>) -> Payload {
+    Payload::map([("ok", true.into()), ("payload", payload.into())])
+}
+
+/// Builds the payload of a failed procedure result
+///
+/// River sends errors as `{ "ok": false, "payload": { "code", "message" } }`,
+/// `code` is usually a [`ProcedureError`](crate::types::ProcedureError) but procedures are free to use
+/// their own codes.
+pub fn error_payload(code: &impl ToString, message: impl Into<String>) -> Payload {
+    Payload::map([
+        ("ok", false.into()),
+        (
+            "payload",
+            Payload::map([
+                ("code", code.to_string().into()),
+                ("message", message.into().into()),
+            ]),
+        ),
+    ])
+}
+
 /// Helper method that converts a [`ProcedureRes`] into an [`OutgoingMessage`]
 ///
 /// The `close` parameter is used to indicate whether this message is
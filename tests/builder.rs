@@ -0,0 +1,362 @@
+//! Servers configured through `RiverServerBuilder`
+
+mod common;
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use common::{SERVICE, STREAM_CLOSED, STREAM_OPEN, TestHandler, loopback_builder};
+use rapids::{
+    dispatch::RiverServer,
+    transport::{CloseReason, loopback::LoopbackClient},
+    types::{HandshakeError, Payload, RiverResult},
+};
+
+#[test]
+fn invalid_configurations_are_rejected() {
+    assert!(RiverServer::<TestHandler>::builder().build().is_err());
+    assert!(loopback_builder().server_id("").build().is_err());
+    assert!(loopback_builder().heartbeats_until_dead(3).build().is_err());
+    assert!(
+        loopback_builder()
+            .heartbeat_interval(Duration::from_secs(1))
+            .heartbeats_until_dead(0)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .max_frame_size(64)
+            .max_payload_size(128)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .max_frame_size(64)
+            .procedure_max_payload_size(SERVICE, "echo", 128)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .stream_channel_capacity(0)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .session_buffer_limit(0, 1024)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .default_timeout(Duration::ZERO)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .max_streams_per_session(0)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .procedure_concurrency_limit(SERVICE, "echo", 0)
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .procedure_timeout(SERVICE, "unknown", Duration::from_secs(1))
+            .build()
+            .is_err()
+    );
+    assert!(
+        loopback_builder()
+            .service_timeout("unknown", Duration::from_secs(1))
+            .build()
+            .is_err()
+    );
+
+    assert!(loopback_builder().build().is_ok());
+}
+
+#[tokio::test]
+async fn huge_durations_are_accepted() {
+    let server = Arc::new(
+        loopback_builder()
+            .heartbeat_interval(Duration::MAX)
+            .heartbeats_until_dead(3)
+            .handshake_timeout(Duration::MAX)
+            .session_grace_period(Duration::MAX)
+            .default_timeout(Duration::MAX)
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        client.recv().await.unwrap().unwrap().header.stream_id,
+        "rpc"
+    );
+}
+
+#[tokio::test]
+async fn messages_are_sent_from_server_id() {
+    let server = Arc::new(loopback_builder().server_id("server-1").build().unwrap());
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.from, "server-1");
+}
+
+#[tokio::test]
+async fn handshake_hook_rejects_clients() {
+    let server = Arc::new(
+        loopback_builder()
+            .on_handshake(|request| match request.metadata {
+                Some(_) => Ok(()),
+                None => Err("metadata is required".to_string()),
+            })
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+
+    match client.handshake().await.unwrap() {
+        RiverResult::Err { code, message } => {
+            assert!(matches!(code, HandshakeError::RejectedByCustomHandler));
+            assert_eq!(message, "metadata is required");
+        }
+        RiverResult::Ok(response) => panic!("handshake was accepted: {response:?}"),
+    }
+}
+
+#[tokio::test]
+async fn session_hooks_are_called() {
+    let connected = Arc::new(AtomicUsize::new(0));
+    let disconnected = Arc::new(AtomicUsize::new(0));
+
+    let server = Arc::new(
+        loopback_builder()
+            .on_connect({
+                let connected = connected.clone();
+                move |_| {
+                    connected.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .on_disconnect({
+                let disconnected = disconnected.clone();
+                move |_| {
+                    disconnected.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .build()
+            .unwrap(),
+    );
+
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+    assert_eq!(connected.load(Ordering::SeqCst), 1);
+
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(disconnected.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn silent_clients_are_disconnected() {
+    let server = Arc::new(
+        loopback_builder()
+            .heartbeat_interval(Duration::from_millis(10))
+            .heartbeats_until_dead(2)
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    // Never answer heartbeats
+    while client.recv_frame().await.is_some() {}
+
+    assert_eq!(client.close_reason(), Some(CloseReason::Unresponsive));
+}
+
+#[tokio::test]
+async fn handshake_response_carries_affinity_token() {
+    let server = Arc::new(
+        loopback_builder()
+            .server_id("instance-a")
+            .affinity_token(|_| Some("instance-a".to_string()))
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+
+    match client.handshake().await.unwrap() {
+        RiverResult::Ok(response) => {
+            assert_eq!(response.affinity_token.as_deref(), Some("instance-a"));
+            assert!(server.owns_session(&response.session_id));
+        }
+        RiverResult::Err { message, .. } => panic!("handshake was rejected: {message}"),
+    }
+}
+
+#[tokio::test]
+async fn sessions_are_owned_until_their_grace_period_ends() {
+    let server = Arc::new(
+        loopback_builder()
+            .session_grace_period(Duration::from_millis(100))
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+
+    let RiverResult::Ok(response) = client.handshake().await.unwrap() else {
+        panic!("handshake was rejected");
+    };
+    client
+        .send_init("slow", SERVICE, "slow", Payload::null(), STREAM_OPEN)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    drop(client);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(server.owns_session(&response.session_id));
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(!server.owns_session(&response.session_id));
+    assert!(!server.owns_session("unknown"));
+}
+
+fn bounded_server() -> Arc<RiverServer<TestHandler>> {
+    Arc::new(
+        loopback_builder()
+            .outgoing_channel_capacity(1)
+            .stream_channel_capacity(1)
+            .build()
+            .unwrap(),
+    )
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_outgoing_channel_does_not_block_the_session() {
+    let server = bounded_server();
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    // Keeps the outgoing channel full while the session rejects a procedure
+    client
+        .send_init(
+            "countdown",
+            SERVICE,
+            "countdown",
+            Payload::new(&10_000).unwrap(),
+            STREAM_OPEN,
+        )
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    client
+        .send_init(
+            "unknown",
+            SERVICE,
+            "unknown",
+            Payload::null(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let mut countdown = 0;
+    let mut rejected = false;
+
+    while countdown < 10_001 || !rejected {
+        let message = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .expect("Session is stuck")
+            .unwrap()
+            .unwrap();
+
+        match message.header.stream_id.as_str() {
+            "countdown" => countdown += 1,
+            "unknown" => rejected = true,
+            stream_id => panic!("Unexpected message on {stream_id}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn procedures_not_reading_their_messages_are_cancelled() {
+    let server = bounded_server();
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init("slow", SERVICE, "slow", Payload::null(), STREAM_OPEN)
+        .await
+        .unwrap();
+    for n in 0..3 {
+        client
+            .send_request("slow", Payload::new(&n).unwrap(), 0)
+            .await
+            .unwrap();
+    }
+
+    let cancel = tokio::time::timeout(Duration::from_secs(5), client.recv())
+        .await
+        .expect("Session is stuck")
+        .unwrap()
+        .unwrap();
+    assert_eq!(cancel.header.stream_id, "slow");
+    assert_eq!(
+        cancel.payload.decode::<serde_json::Value>().unwrap()["payload"]["code"],
+        "CANCEL"
+    );
+
+    // The session keeps serving other procedures
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.stream_id, "rpc");
+}
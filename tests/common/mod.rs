@@ -0,0 +1,456 @@
+//! Shared setup for integration tests
+//!
+//! Provides a [`RiverServer`] with a procedure of each type, and a minimal
+//! client that speaks the protocol directly so tests can control every
+//! message that goes over the wire.
+
+#![allow(dead_code, reason = "Not every test uses every helper")]
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use kanal::{AsyncReceiver, AsyncSender};
+use rapids::{
+    codecs,
+    dispatch::{RiverServer, RiverServerBuilder, ServiceHandler},
+    transport::loopback::LoopbackClient,
+    types::{
+        Codec, Control, ExpectedSessionState, HandshakeError, HandshakeRequest,
+        HandshakeResponseOk, Header, IncomingMessage, OutgoingMessage, Payload, ProcedureError,
+        ProcedureRes, ProtocolVersion, RPCMetadata, RawPayload, RequestInner, RiverResult,
+        TransportCodec, TransportControlMessage, TransportRequestMessage,
+    },
+    utils::{self, generate_id},
+};
+use serde::Deserialize;
+use tokio::{net::TcpStream, time};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream,
+    tungstenite::{
+        Message, client::IntoClientRequest, handshake::client::Request,
+        http::header::SEC_WEBSOCKET_PROTOCOL,
+    },
+};
+
+/// Name of the only service served by [`TestHandler`]
+pub const SERVICE: &str = "test";
+
+/// Control flag marking the first message of a stream
+pub const STREAM_OPEN: i32 = 0b0010;
+/// Control flag marking the last message of a stream
+pub const STREAM_CLOSED: i32 = 0b1000;
+/// Control flag marking a stream as cancelled
+pub const STREAM_CANCEL: i32 = 0b0100;
+/// Control flag used by heartbeats
+pub const ACK: i32 = 0b0001;
+
+/// How long to wait for a message before failing a test
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Service handler with one procedure of each River procedure type
+///
+/// - `echo` (rpc): responds with its payload
+/// - `fail` (rpc): responds with an `UNCAUGHT_ERROR`
+/// - `sum` (upload): sums `{ "n": number }` requests until closed
+/// - `echoStream` (stream): echoes every request until closed
+/// - `countdown` (subscription): sends `n` down to 1, then closes
+/// - `slow` (rpc): never finishes
+/// - `abandon` (stream): returns without reading or closing its stream
+pub struct TestHandler;
+
+#[derive(Deserialize)]
+struct Number {
+    n: i64,
+}
+
+impl ServiceHandler for TestHandler {
+    fn description(&self) -> HashMap<String, Vec<String>> {
+        let procedures = [
+            "echo",
+            "fail",
+            "sum",
+            "echoStream",
+            "countdown",
+            "slow",
+            "abandon",
+        ];
+
+        HashMap::from([(SERVICE.to_string(), procedures.map(String::from).to_vec())])
+    }
+
+    async fn invoke_rpc(
+        &self,
+        _service: String,
+        procedure: String,
+        metadata: RPCMetadata,
+        channel: AsyncSender<OutgoingMessage>,
+        payload: RawPayload,
+        recv: AsyncReceiver<IncomingMessage>,
+    ) {
+        let send = async |response: ProcedureRes, close: bool, error: bool| {
+            channel
+                .send(utils::payload_to_msg(response, &metadata, close, error))
+                .await
+                .ok();
+        };
+
+        match procedure.as_str() {
+            "echo" => {
+                let value: serde_json::Value = payload.decode().unwrap();
+                send(
+                    ProcedureRes::Response(utils::ok_payload(value)),
+                    true,
+                    false,
+                )
+                .await;
+            }
+            "fail" => {
+                let error = utils::error_payload(&ProcedureError::UncaughtError, "failed");
+                send(ProcedureRes::Response(error), true, true).await;
+            }
+            "sum" => {
+                let mut total = 0;
+
+                loop {
+                    match recv.recv().await {
+                        Ok(IncomingMessage::Request(payload)) => {
+                            total += payload.decode::<Number>().unwrap().n;
+                        }
+                        Ok(IncomingMessage::Close) => break,
+                        Ok(IncomingMessage::ForceClose) | Err(_) => return,
+                    }
+                }
+
+                let response = utils::ok_payload(serde_json::json!({ "total": total }));
+                send(ProcedureRes::Response(response), true, false).await;
+            }
+            "echoStream" => loop {
+                match recv.recv().await {
+                    Ok(IncomingMessage::Request(payload)) => {
+                        let value: serde_json::Value = payload.decode().unwrap();
+                        send(
+                            ProcedureRes::Response(utils::ok_payload(value)),
+                            false,
+                            false,
+                        )
+                        .await;
+                    }
+                    Ok(IncomingMessage::Close) => {
+                        send(ProcedureRes::Close, true, false).await;
+                        break;
+                    }
+                    Ok(IncomingMessage::ForceClose) | Err(_) => break,
+                }
+            },
+            "countdown" => {
+                let start: i64 = payload.decode().unwrap();
+
+                for n in (1..=start).rev() {
+                    let response = utils::ok_payload(serde_json::json!(n));
+                    send(ProcedureRes::Response(response), false, false).await;
+                }
+
+                send(ProcedureRes::Close, true, false).await;
+            }
+            "slow" => time::sleep(Duration::from_secs(3600)).await,
+            "abandon" => {}
+            _ => unreachable!("Dispatcher only invokes described procedures"),
+        }
+    }
+}
+
+/// Decodes a hex fixture, ignoring whitespace
+pub fn hex(fixture: &str) -> Vec<u8> {
+    let digits: Vec<u8> = fixture
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+/// Configures a [`TestHandler`] server without heartbeats, so tests only see the messages they cause
+pub fn loopback_builder() -> RiverServerBuilder<TestHandler> {
+    RiverServer::builder()
+        .codec(codecs::BinaryCodec {})
+        .handler(TestHandler)
+        .heartbeat_interval(Duration::ZERO)
+}
+
+/// A server from [`loopback_builder`] with default settings
+pub fn loopback_server() -> Arc<RiverServer<TestHandler>> {
+    Arc::new(loopback_builder().build().unwrap())
+}
+
+/// Connects to `server` over the loopback transport and completes the handshake
+pub async fn connect_loopback(server: &Arc<RiverServer<TestHandler>>) -> LoopbackClient {
+    let mut client = LoopbackClient::connect(server);
+    let response = client.handshake().await.unwrap();
+    assert!(response.is_ok(), "{response:?}");
+
+    client
+}
+
+/// Serves `server` on a random local port, returning its address
+pub async fn spawn_server(server: RiverServer<TestHandler>) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(Arc::new(server).serve_listener(listener));
+
+    addr
+}
+
+/// A message received by a [`TestClient`]
+#[derive(Debug)]
+pub struct Received {
+    pub header: Header,
+    pub payload: serde_json::Value,
+}
+
+/// Client that sends raw River messages
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    codec_name: String,
+    codec: Arc<dyn TransportCodec>,
+    seq: i32,
+}
+
+impl TestClient {
+    /// The id the client identifies itself with
+    pub const ID: &str = "client";
+
+    /// Connects to a server using the built-in codec called `codec`
+    pub async fn connect(addr: SocketAddr, codec: &str) -> Self {
+        let request = format!("ws://{addr}/").into_client_request().unwrap();
+
+        Self::connect_with(request, codec).await.0
+    }
+
+    /// Connects with a custom upgrade request, returning the subprotocol
+    /// the server accepted
+    pub async fn connect_with(request: Request, codec: &str) -> (Self, Option<String>) {
+        let (ws, response) = tokio_tungstenite::connect_async(request).await.unwrap();
+        let subprotocol = response
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .map(|protocol| protocol.to_str().unwrap().to_string());
+
+        let client = Self {
+            ws,
+            codec_name: codec.to_string(),
+            codec: codecs::by_name(codec).unwrap(),
+            seq: 0,
+        };
+
+        (client, subprotocol)
+    }
+
+    /// Serves `server`, connects to it and completes the handshake
+    pub async fn start(server: RiverServer<TestHandler>, codec: &str) -> Self {
+        let mut client = Self::connect(spawn_server(server).await, codec).await;
+
+        let response = client.handshake(ProtocolVersion::V2_0).await;
+        assert!(response.is_ok(), "Handshake failed: {response:?}");
+
+        client
+    }
+
+    fn header(&mut self, stream_id: &str, control_flags: i32) -> Header {
+        let header = Header {
+            id: generate_id(),
+            from: Self::ID.to_string(),
+            to: "SERVER".to_string(),
+            seq: self.seq,
+            ack: 0,
+            stream_id: stream_id.to_string(),
+            control_flags,
+        };
+
+        self.seq += 1;
+        header
+    }
+
+    /// Sends a handshake request, returning the server's response
+    pub async fn handshake(
+        &mut self,
+        protocol_version: ProtocolVersion,
+    ) -> RiverResult<HandshakeResponseOk, HandshakeError> {
+        let session_id = generate_id();
+        let request = Control::HandshakeRequest(HandshakeRequest {
+            protocol_version,
+            session_id,
+            expected_session_state: ExpectedSessionState::default(),
+            metadata: None,
+        });
+        self.send_control(&generate_id(), request, 0).await;
+
+        self.recv_handshake_response().await
+    }
+
+    /// Sends a v1.1 handshake request without `expectedSessionState`,
+    /// returning the server's response
+    pub async fn handshake_v1_1(&mut self) -> RiverResult<HandshakeResponseOk, HandshakeError> {
+        let header = self.header(&generate_id(), 0);
+        let request = serde_json::json!({
+            "id": header.id,
+            "from": header.from,
+            "to": header.to,
+            "seq": header.seq,
+            "ack": header.ack,
+            "streamId": header.stream_id,
+            "controlFlags": header.control_flags,
+            "payload": {
+                "type": "HANDSHAKE_REQ",
+                "protocolVersion": "v1.1",
+                "sessionId": generate_id(),
+                // v1.1 clients only send what they expect from the server
+                "expectedSessionState": { "reconnect": false, "nextExpectedSeq": 0 },
+            },
+        });
+
+        let data = match self.codec_name.as_str() {
+            "json" => codecs::NaiveCodec {}.encode_to_vec(&request),
+            "msgpack" => codecs::BinaryCodec {}.encode_to_vec(&request),
+            "cbor" => codecs::CborCodec {}.encode_to_vec(&request),
+            name => panic!("Unknown codec {name}"),
+        };
+        self.send_raw(data.unwrap()).await;
+
+        self.recv_handshake_response().await
+    }
+
+    async fn recv_handshake_response(
+        &mut self,
+    ) -> RiverResult<HandshakeResponseOk, HandshakeError> {
+        let frame = self.recv_frame().await.expect("Connection closed");
+        let response = self.codec.decode_control(&frame.into_data()).unwrap();
+
+        match response.payload {
+            Control::HandshakeResponse(response) => response.status.try_into().unwrap(),
+            payload => panic!("Expected a handshake response, got {payload:?}"),
+        }
+    }
+
+    /// Sends the first message of a stream
+    pub async fn send_init(
+        &mut self,
+        stream_id: &str,
+        procedure: &str,
+        payload: serde_json::Value,
+        control_flags: i32,
+    ) {
+        let message = TransportRequestMessage {
+            header: self.header(stream_id, control_flags),
+            inner: RequestInner::Init {
+                service_name: SERVICE.to_string(),
+                procedure_name: procedure.to_string(),
+                payload: payload.into(),
+            },
+        };
+
+        self.send_raw(self.codec.encode_request(&message).unwrap())
+            .await;
+    }
+
+    /// Sends the first message of a stream to any service
+    pub async fn send_init_to(&mut self, stream_id: &str, service: &str, procedure: &str) {
+        let message = TransportRequestMessage {
+            header: self.header(stream_id, STREAM_OPEN | STREAM_CLOSED),
+            inner: RequestInner::Init {
+                service_name: service.to_string(),
+                procedure_name: procedure.to_string(),
+                payload: Payload::null(),
+            },
+        };
+
+        self.send_raw(self.codec.encode_request(&message).unwrap())
+            .await;
+    }
+
+    /// Sends a message on an open stream
+    pub async fn send_request(
+        &mut self,
+        stream_id: &str,
+        payload: serde_json::Value,
+        control_flags: i32,
+    ) {
+        let message = TransportRequestMessage {
+            header: self.header(stream_id, control_flags),
+            inner: RequestInner::Request {
+                payload: payload.into(),
+            },
+        };
+
+        self.send_raw(self.codec.encode_request(&message).unwrap())
+            .await;
+    }
+
+    /// Sends a control message
+    pub async fn send_control(&mut self, stream_id: &str, payload: Control, control_flags: i32) {
+        let message = TransportControlMessage {
+            header: self.header(stream_id, control_flags),
+            payload,
+        };
+
+        self.send_raw(self.codec.encode_control(&message).unwrap())
+            .await;
+    }
+
+    /// Sends a binary frame as-is
+    pub async fn send_raw(&mut self, data: Vec<u8>) {
+        self.ws.send(Message::Binary(data.into())).await.unwrap();
+    }
+
+    /// Sends a text frame as-is
+    pub async fn send_text(&mut self, data: String) {
+        self.ws.send(Message::Text(data.into())).await.unwrap();
+    }
+
+    /// Receives the next data frame, or `None` if the connection closed
+    pub async fn recv_frame(&mut self) -> Option<Message> {
+        loop {
+            let message = time::timeout(RECV_TIMEOUT, self.ws.next())
+                .await
+                .expect("Timed out waiting for a message")?
+                .ok()?;
+
+            match message {
+                Message::Binary(_) | Message::Text(_) | Message::Close(_) => {
+                    return Some(message);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Receives the next message, including heartbeats
+    pub async fn recv_any(&mut self) -> Received {
+        let data = self
+            .recv_frame()
+            .await
+            .expect("Connection closed")
+            .into_data();
+        let frame = self.codec.decode_frame(&data).unwrap();
+        let payload = RawPayload::new(frame.payload.into_owned(), self.codec.clone());
+
+        Received {
+            header: frame.header,
+            payload: payload.decode().unwrap(),
+        }
+    }
+
+    /// Receives the next message that isn't a heartbeat
+    pub async fn recv(&mut self) -> Received {
+        loop {
+            let message = self.recv_any().await;
+
+            if message.header.stream_id != "heartbeat" {
+                return message;
+            }
+        }
+    }
+}
@@ -0,0 +1,446 @@
+//! River protocol conformance tests
+//!
+//! Each scenario drives a [`RiverServer`] over a real WebSocket connection
+//! and checks the messages it sends back. Every scenario runs once per
+//! built-in codec.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{
+    ACK, STREAM_CANCEL, STREAM_CLOSED, STREAM_OPEN, TestClient, TestHandler, hex, spawn_server,
+};
+use rapids::{
+    codecs,
+    dispatch::{RiverServer, RiverServerBuilder},
+    types::{Control, HandshakeError, ProtocolVersion, RiverResult},
+};
+use serde_json::json;
+use tokio::time;
+use tokio_tungstenite::tungstenite::{Message, protocol::frame::coding::CloseCode};
+
+/// Control flag v1.1 marks the last message of a stream with
+const STREAM_CLOSED_V1_1: i32 = 0b0100;
+
+fn builder(codec: &str) -> RiverServerBuilder<TestHandler> {
+    RiverServer::builder()
+        .codec(codecs::by_name(codec).unwrap())
+        .handler(TestHandler)
+}
+
+fn server(codec: &str) -> RiverServer<TestHandler> {
+    builder(codec).build().unwrap()
+}
+
+async fn handshake_succeeds(codec: &str) {
+    let addr = spawn_server(server(codec)).await;
+    let mut client = TestClient::connect(addr, codec).await;
+
+    let response = client.handshake(ProtocolVersion::V2_0).await;
+
+    assert!(response.is_ok(), "{response:?}");
+}
+
+async fn handshake_rejects_unsupported_version(codec: &str) {
+    let addr = spawn_server(server(codec)).await;
+    let mut client = TestClient::connect(addr, codec).await;
+
+    let response = client.handshake(ProtocolVersion::V1_1).await;
+
+    assert!(
+        matches!(
+            response,
+            RiverResult::Err {
+                code: HandshakeError::ProtocolVersionMismatch,
+                ..
+            }
+        ),
+        "{response:?}"
+    );
+}
+
+async fn v1_1_handshake_succeeds_when_enabled(codec: &str) {
+    let addr = spawn_server(builder(codec).v1_1_compatibility().build().unwrap()).await;
+    let mut client = TestClient::connect(addr, codec).await;
+
+    // v1.1 clients don't send `expectedSessionState`
+    let response = client.handshake_v1_1().await;
+
+    assert!(response.is_ok(), "{response:?}");
+}
+
+async fn v1_1_close_flags_are_translated(codec: &str) {
+    let addr = spawn_server(builder(codec).v1_1_compatibility().build().unwrap()).await;
+    let mut client = TestClient::connect(addr, codec).await;
+    let response = client.handshake_v1_1().await;
+    assert!(response.is_ok(), "{response:?}");
+
+    // v1.1 closes with the bit v2.0 cancels with, so the upload only
+    // responds if the close is read as one
+    client
+        .send_init("upload", "sum", json!(null), STREAM_OPEN)
+        .await;
+    client.send_request("upload", json!({ "n": 4 }), 0).await;
+    client
+        .send_control("upload", Control::Close, STREAM_CLOSED_V1_1)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "upload");
+    assert_eq!(response.header.control_flags, STREAM_CLOSED_V1_1);
+    assert_eq!(
+        response.payload,
+        json!({ "ok": true, "payload": { "total": 4 } })
+    );
+
+    client
+        .send_init("rpc", "echo", json!(1), STREAM_OPEN | STREAM_CLOSED_V1_1)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.header.control_flags, STREAM_CLOSED_V1_1);
+    assert_eq!(response.payload, json!({ "ok": true, "payload": 1 }));
+}
+
+async fn v1_1_cancellations_are_sent_as_closes(codec: &str) {
+    let addr = spawn_server(builder(codec).v1_1_compatibility().build().unwrap()).await;
+    let mut client = TestClient::connect(addr, codec).await;
+    let response = client.handshake_v1_1().await;
+    assert!(response.is_ok(), "{response:?}");
+
+    client
+        .send_init("rpc", "fail", json!(null), STREAM_OPEN | STREAM_CLOSED_V1_1)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.control_flags, STREAM_CLOSED_V1_1);
+    assert_eq!(response.payload["ok"], json!(false));
+    assert_eq!(response.payload["payload"]["code"], json!("UNCAUGHT_ERROR"));
+}
+
+async fn rpc_responds_and_closes(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client
+        .send_init(
+            "rpc",
+            "echo",
+            json!({ "hello": "world" }),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.header.to, TestClient::ID);
+    assert_eq!(response.header.control_flags, STREAM_CLOSED);
+    assert_eq!(
+        response.payload,
+        json!({ "ok": true, "payload": { "hello": "world" } })
+    );
+}
+
+async fn rpc_error_cancels_stream(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client
+        .send_init("rpc", "fail", json!(null), STREAM_OPEN | STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.control_flags, STREAM_CANCEL);
+    assert_eq!(response.payload["ok"], json!(false));
+    assert_eq!(response.payload["payload"]["code"], json!("UNCAUGHT_ERROR"));
+}
+
+async fn upload_responds_after_close(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client
+        .send_init("upload", "sum", json!(null), STREAM_OPEN)
+        .await;
+    for n in 1..=3 {
+        client.send_request("upload", json!({ "n": n }), 0).await;
+    }
+    client
+        .send_control("upload", Control::Close, STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "upload");
+    assert_eq!(response.header.control_flags, STREAM_CLOSED);
+    assert_eq!(
+        response.payload,
+        json!({ "ok": true, "payload": { "total": 6 } })
+    );
+}
+
+async fn stream_echoes_until_close(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client
+        .send_init("stream", "echoStream", json!(null), STREAM_OPEN)
+        .await;
+
+    for n in 1..=3 {
+        client.send_request("stream", json!(n), 0).await;
+
+        let response = client.recv().await;
+        assert_eq!(response.header.stream_id, "stream");
+        assert_eq!(response.header.control_flags, 0);
+        assert_eq!(response.payload, json!({ "ok": true, "payload": n }));
+    }
+
+    client
+        .send_control("stream", Control::Close, STREAM_CLOSED)
+        .await;
+
+    let close = client.recv().await;
+    assert_eq!(close.header.stream_id, "stream");
+    assert_eq!(close.header.control_flags, STREAM_CLOSED);
+    assert_eq!(close.payload, json!({ "type": "CLOSE" }));
+}
+
+async fn subscription_streams_until_done(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client
+        .send_init(
+            "subscription",
+            "countdown",
+            json!(3),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await;
+
+    for n in (1..=3).rev() {
+        let response = client.recv().await;
+        assert_eq!(response.header.control_flags, 0);
+        assert_eq!(response.payload, json!({ "ok": true, "payload": n }));
+    }
+
+    let close = client.recv().await;
+    assert_eq!(close.header.stream_id, "subscription");
+    assert_eq!(close.header.control_flags, STREAM_CLOSED);
+}
+
+async fn timeout_cancels_procedure(codec: &str) {
+    let server = builder(codec)
+        .procedure_timeout("test", "slow", Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let mut client = TestClient::start(server, codec).await;
+
+    client
+        .send_init("slow", "slow", json!(null), STREAM_OPEN | STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "slow");
+    assert_eq!(response.header.control_flags, STREAM_CANCEL);
+    assert_eq!(response.payload["payload"]["code"], json!("CANCEL"));
+}
+
+async fn client_cancel_ends_stream(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client
+        .send_init("stream", "echoStream", json!(null), STREAM_OPEN)
+        .await;
+    client
+        .send_request(
+            "stream",
+            json!({ "ok": false, "payload": { "code": "CANCEL", "message": "cancelled" } }),
+            STREAM_CANCEL,
+        )
+        .await;
+
+    // Requests for the cancelled stream are dropped, so the next message
+    // has to be the response to the rpc.
+    client.send_request("stream", json!(1), 0).await;
+    client
+        .send_init("rpc", "echo", json!(2), STREAM_OPEN | STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+}
+
+async fn server_sends_heartbeats(codec: &str) {
+    let server = RiverServer::new_with_heartbeat_interval(
+        codecs::by_name(codec).unwrap(),
+        TestHandler,
+        Duration::from_millis(20),
+    );
+    let mut client = TestClient::start(server, codec).await;
+
+    let heartbeat = client.recv_any().await;
+    assert_eq!(heartbeat.header.control_flags, ACK);
+    assert_eq!(heartbeat.payload, json!({ "type": "ACK" }));
+}
+
+async fn client_heartbeats_are_accepted(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client.send_control("heartbeat", Control::Ack, ACK).await;
+    client
+        .send_init("rpc", "echo", json!(1), STREAM_OPEN | STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+}
+
+async fn unknown_procedure_is_rejected(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client.send_init_to("rpc", "test", "missing").await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.header.control_flags, STREAM_CANCEL);
+    assert_eq!(
+        response.payload["payload"]["code"],
+        json!("INVALID_REQUEST")
+    );
+}
+
+async fn unknown_service_is_rejected(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client.send_init_to("rpc", "missing", "echo").await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.header.control_flags, STREAM_CANCEL);
+    assert_eq!(
+        response.payload["payload"]["code"],
+        json!("INVALID_REQUEST")
+    );
+}
+
+async fn malformed_frame_is_ignored(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client.send_raw(b"not a river message".to_vec()).await;
+    client
+        .send_init("rpc", "echo", json!(1), STREAM_OPEN | STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.payload, json!({ "ok": true, "payload": 1 }));
+}
+
+async fn messages_for_finished_procedures_are_ignored(codec: &str) {
+    let mut client = TestClient::start(server(codec), codec).await;
+
+    client
+        .send_init("stream", "abandon", json!(null), STREAM_OPEN)
+        .await;
+    time::sleep(Duration::from_millis(20)).await;
+
+    // The procedure has returned without closing, so nothing reads this
+    client.send_request("stream", json!(1), 0).await;
+    client
+        .send_init("rpc", "echo", json!(2), STREAM_OPEN | STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.payload, json!({ "ok": true, "payload": 2 }));
+}
+
+async fn oversized_frames_close_the_connection(codec: &str) {
+    // Heartbeats would race the close frame
+    let server = builder(codec)
+        .heartbeat_interval(Duration::ZERO)
+        .max_frame_size(1024)
+        .build()
+        .unwrap();
+    let mut client = TestClient::start(server, codec).await;
+
+    client.send_raw(vec![0; 4096]).await;
+
+    match client.recv_frame().await {
+        Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Size),
+        message => panic!("Expected a close frame, got {message:?}"),
+    }
+}
+
+/// Frames encoded the way the TypeScript client encodes them, see `wire.rs`
+#[tokio::test]
+async fn typescript_client_frames_are_served() {
+    let addr = spawn_server(server("msgpack")).await;
+    let mut client = TestClient::connect(addr, "msgpack").await;
+
+    client
+        .send_raw(hex(include_str!("fixtures/handshake.msgpack.hex")))
+        .await;
+    let handshake = client.recv().await;
+    assert_eq!(handshake.header.to, "client-1");
+    assert_eq!(handshake.payload["type"], json!("HANDSHAKE_RESP"));
+    assert_eq!(handshake.payload["status"]["ok"], json!(true));
+    assert_eq!(handshake.payload["status"]["sessionId"], json!("session-1"));
+
+    client
+        .send_raw(hex(include_str!("fixtures/request.msgpack.hex")))
+        .await;
+    let response = client.recv().await;
+    assert_eq!(response.header.to, "client-1");
+    assert_eq!(response.header.stream_id, "stream-1");
+    assert_eq!(response.header.control_flags, STREAM_CLOSED);
+    assert_eq!(
+        response.payload,
+        json!({ "ok": true, "payload": { "hello": "world" } })
+    );
+}
+
+macro_rules! conformance {
+    ($($codec:ident: [$($scenario:ident),* $(,)?]),* $(,)?) => {
+        $(
+            mod $codec {
+                $(
+                    #[tokio::test]
+                    async fn $scenario() {
+                        super::$scenario(stringify!($codec)).await;
+                    }
+                )*
+            }
+        )*
+    };
+}
+
+macro_rules! scenarios {
+    ($($codec:ident),*) => {
+        conformance! {
+            $(
+                $codec: [
+                    handshake_succeeds,
+                    handshake_rejects_unsupported_version,
+                    v1_1_handshake_succeeds_when_enabled,
+                    v1_1_close_flags_are_translated,
+                    v1_1_cancellations_are_sent_as_closes,
+                    rpc_responds_and_closes,
+                    rpc_error_cancels_stream,
+                    upload_responds_after_close,
+                    stream_echoes_until_close,
+                    subscription_streams_until_done,
+                    timeout_cancels_procedure,
+                    client_cancel_ends_stream,
+                    server_sends_heartbeats,
+                    client_heartbeats_are_accepted,
+                    unknown_procedure_is_rejected,
+                    unknown_service_is_rejected,
+                    malformed_frame_is_ignored,
+                    messages_for_finished_procedures_are_ignored,
+                    oversized_frames_close_the_connection,
+                ],
+            )*
+        }
+    };
+}
+
+scenarios!(json, msgpack, cbor);
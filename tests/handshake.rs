@@ -0,0 +1,136 @@
+//! Connections that never complete a valid handshake
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::{TestClient, TestHandler, loopback_server, spawn_server};
+use rapids::{
+    codecs::BinaryCodec,
+    dispatch::RiverServer,
+    transport::{CloseReason, Frame, loopback::LoopbackClient},
+    types::{Control, HandshakeError, RiverResult, TransportCodec},
+};
+use tokio_tungstenite::tungstenite::{Message, protocol::frame::coding::CloseCode};
+
+/// Receives the handshake response, expecting it to be a `MALFORMED_HANDSHAKE` error
+async fn assert_malformed(client: &mut LoopbackClient) {
+    let frame = client.recv_frame().await.unwrap().unwrap();
+    let Control::HandshakeResponse(response) =
+        BinaryCodec {}.decode_control(&frame.data).unwrap().payload
+    else {
+        panic!("Expected a handshake response");
+    };
+
+    match response.status.try_into().unwrap() {
+        RiverResult::Err { code, .. } => {
+            assert!(matches!(code, HandshakeError::MalformedHandshake));
+        }
+        RiverResult::Ok(response) => panic!("handshake was accepted: {response:?}"),
+    }
+
+    assert!(client.recv_frame().await.is_none());
+    assert_eq!(client.close_reason(), Some(CloseReason::ProtocolError));
+}
+
+#[tokio::test]
+async fn undecodable_handshake_is_rejected() {
+    let mut client = LoopbackClient::connect(&loopback_server());
+
+    client
+        .send_frame(Frame::binary(vec![0xc1, 0x00, 0xff]))
+        .await
+        .unwrap();
+
+    assert_malformed(&mut client).await;
+}
+
+#[tokio::test]
+async fn first_message_must_be_a_handshake() {
+    let mut client = LoopbackClient::connect(&loopback_server());
+
+    client
+        .send_control("heartbeat", Control::Ack, 0b0001)
+        .await
+        .unwrap();
+
+    assert_malformed(&mut client).await;
+}
+
+#[tokio::test]
+async fn rejected_handshakes_close_the_connection() {
+    let server = Arc::new(
+        RiverServer::builder()
+            .codec(BinaryCodec {})
+            .handler(TestHandler)
+            .on_handshake(|_| Err("no".to_string()))
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+
+    assert!(client.handshake().await.unwrap().is_err());
+    assert!(client.recv_frame().await.is_none());
+    assert_eq!(client.close_reason(), Some(CloseReason::Normal));
+}
+
+#[tokio::test]
+async fn slow_handshakes_time_out() {
+    let server = Arc::new(
+        RiverServer::builder()
+            .codec(BinaryCodec {})
+            .handler(TestHandler)
+            .handshake_timeout(Duration::from_millis(20))
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+
+    assert!(client.recv_frame().await.is_none());
+    assert_eq!(client.close_reason(), Some(CloseReason::HandshakeTimeout));
+}
+
+#[tokio::test]
+async fn slow_handshakes_close_websockets_with_policy_violation() {
+    let mut client = TestClient::connect(
+        spawn_server(
+            RiverServer::builder()
+                .codec(BinaryCodec {})
+                .handler(TestHandler)
+                .handshake_timeout(Duration::from_millis(20))
+                .build()
+                .unwrap(),
+        )
+        .await,
+        "msgpack",
+    )
+    .await;
+
+    match client.recv_frame().await {
+        Some(Message::Close(Some(frame))) => {
+            assert_eq!(frame.code, CloseCode::Policy);
+            assert_eq!(frame.reason, "Handshake timed out");
+        }
+        message => panic!("Expected a close frame, got {message:?}"),
+    }
+}
+
+#[tokio::test]
+async fn malformed_handshake_closes_websocket_with_protocol_error() {
+    let mut client = TestClient::connect(
+        spawn_server(RiverServer::new(BinaryCodec {}, TestHandler)).await,
+        "msgpack",
+    )
+    .await;
+
+    client.send_raw(vec![0xc1, 0x00, 0xff]).await;
+
+    assert!(matches!(
+        client.recv_frame().await,
+        Some(Message::Binary(_))
+    ));
+    match client.recv_frame().await {
+        Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Protocol),
+        message => panic!("Expected a close frame, got {message:?}"),
+    }
+}
@@ -0,0 +1,234 @@
+//! Rate and concurrency limits applied to sessions
+
+mod common;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::{
+    SERVICE, STREAM_CANCEL, STREAM_CLOSED, STREAM_OPEN, TestHandler, connect_loopback,
+    loopback_builder,
+};
+use rapids::{
+    dispatch::{RiverServer, RiverServerBuilder},
+    limits::{RateLimit, RateLimitAction},
+    transport::{CloseReason, loopback::LoopbackClient},
+    types::Payload,
+};
+use serde_json::{Value, json};
+
+fn rate_limited_server(action: RateLimitAction) -> Arc<RiverServer<TestHandler>> {
+    limited_server(|builder| builder.rate_limit(RateLimit::new(action).messages_per_second(2.0)))
+}
+
+fn limited_server(
+    configure: impl FnOnce(RiverServerBuilder<TestHandler>) -> RiverServerBuilder<TestHandler>,
+) -> Arc<RiverServer<TestHandler>> {
+    Arc::new(configure(loopback_builder()).build().unwrap())
+}
+
+async fn call_echo(client: &mut LoopbackClient, stream_id: &str) {
+    call(client, stream_id, "echo").await;
+}
+
+async fn call(client: &mut LoopbackClient, stream_id: &str, procedure: &str) {
+    client
+        .send_init(
+            stream_id,
+            SERVICE,
+            procedure,
+            Payload::new(stream_id).unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+}
+
+/// Receives the next response, returning its stream and payload
+async fn recv(client: &mut LoopbackClient) -> (String, Value) {
+    let response = client.recv().await.unwrap().unwrap();
+
+    (
+        response.header.stream_id,
+        response.payload.decode().unwrap(),
+    )
+}
+
+/// Calls `echo`, asserting that it isn't rejected
+async fn assert_echo_runs(client: &mut LoopbackClient, stream_id: &str) {
+    // Slots are released just after a procedure's last message is sent
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    call_echo(client, stream_id).await;
+    assert_eq!(
+        recv(client).await,
+        (
+            stream_id.to_string(),
+            json!({ "ok": true, "payload": stream_id })
+        )
+    );
+}
+
+fn error_code(payload: &Value) -> Option<&str> {
+    payload["payload"]["code"].as_str()
+}
+
+#[test]
+fn invalid_rates_are_rejected() {
+    let build = |rate_limit| loopback_builder().rate_limit(rate_limit).build();
+    let rate_limit = RateLimit::new(RateLimitAction::Delay);
+
+    for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+        assert!(build(rate_limit.messages_per_second(rate)).is_err());
+        assert!(build(rate_limit.bytes_per_second(rate)).is_err());
+    }
+
+    assert!(build(rate_limit.messages_per_second(0.5).bytes_per_second(1024.0)).is_ok());
+    assert!(build(rate_limit).is_ok());
+}
+
+#[tokio::test]
+async fn delayed_sessions_keep_sending() {
+    let mut client = connect_loopback(&rate_limited_server(RateLimitAction::Delay)).await;
+    let start = Instant::now();
+
+    // Two calls fit in the burst, the third stops the session from reading
+    // the fourth until it is back within its limit
+    for stream_id in ["a", "b", "c", "d"] {
+        call_echo(&mut client, stream_id).await;
+    }
+
+    // Responses are sent while the session waits, not after
+    let first = client.recv().await.unwrap().unwrap();
+    assert!(start.elapsed() < Duration::from_millis(250));
+
+    let mut answered = vec![first.header.stream_id];
+    while answered.len() < 4 {
+        let response = client.recv().await.unwrap().unwrap();
+        answered.push(response.header.stream_id);
+    }
+    answered.sort();
+
+    assert_eq!(answered, ["a", "b", "c", "d"]);
+    assert!(start.elapsed() >= Duration::from_millis(450));
+}
+
+#[tokio::test]
+async fn rejected_streams_are_cancelled() {
+    let mut client = connect_loopback(&rate_limited_server(RateLimitAction::RejectStreams)).await;
+
+    for stream_id in ["a", "b", "c"] {
+        call_echo(&mut client, stream_id).await;
+    }
+
+    let mut responses = Vec::new();
+    for _ in 0..3 {
+        let response = client.recv().await.unwrap().unwrap();
+        let payload = response.payload.decode::<Value>().unwrap();
+        responses.push((response.header.stream_id, payload));
+    }
+    responses.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    assert_eq!(responses[0].1, json!({ "ok": true, "payload": "a" }));
+    assert_eq!(responses[1].1, json!({ "ok": true, "payload": "b" }));
+    assert_eq!(error_code(&responses[2].1), Some("CANCEL"));
+}
+
+#[tokio::test]
+async fn sessions_over_their_limit_are_disconnected() {
+    let mut client = connect_loopback(&rate_limited_server(RateLimitAction::Disconnect)).await;
+
+    for stream_id in ["a", "b", "c"] {
+        call_echo(&mut client, stream_id).await;
+    }
+
+    while client.recv().await.unwrap().is_some() {}
+    assert_eq!(
+        client.transport().close_reason(),
+        Some(CloseReason::RateLimited)
+    );
+}
+
+#[tokio::test]
+async fn streams_past_the_session_limit_are_rejected() {
+    let server = limited_server(|builder| builder.max_streams_per_session(2));
+    let mut client = connect_loopback(&server).await;
+
+    call(&mut client, "a", "slow").await;
+    call(&mut client, "b", "slow").await;
+    call_echo(&mut client, "c").await;
+
+    let (stream_id, payload) = recv(&mut client).await;
+    assert_eq!(stream_id, "c");
+    assert_eq!(error_code(&payload), Some("CANCEL"));
+
+    // The limit applies to each session on its own
+    let mut other = connect_loopback(&server).await;
+    assert_echo_runs(&mut other, "d").await;
+}
+
+#[tokio::test]
+async fn invocations_past_the_procedure_limit_are_rejected() {
+    let server = limited_server(|builder| builder.procedure_concurrency_limit(SERVICE, "slow", 1));
+    let mut first = connect_loopback(&server).await;
+    let mut second = connect_loopback(&server).await;
+
+    call(&mut first, "a", "slow").await;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    call(&mut second, "b", "slow").await;
+
+    let (stream_id, payload) = recv(&mut second).await;
+    assert_eq!(stream_id, "b");
+    assert_eq!(error_code(&payload), Some("CANCEL"));
+
+    // Other procedures are not limited
+    assert_echo_runs(&mut second, "c").await;
+}
+
+#[tokio::test]
+async fn slots_are_released_when_procedures_finish() {
+    let server = limited_server(|builder| builder.max_streams_per_session(1));
+    let mut client = connect_loopback(&server).await;
+
+    assert_echo_runs(&mut client, "a").await;
+    assert_echo_runs(&mut client, "b").await;
+}
+
+#[tokio::test]
+async fn slots_are_released_when_clients_cancel() {
+    let server = limited_server(|builder| builder.max_streams_per_session(1));
+    let mut client = connect_loopback(&server).await;
+
+    client
+        .send_init("a", SERVICE, "echoStream", Payload::null(), STREAM_OPEN)
+        .await
+        .unwrap();
+    let cancel = json!({ "ok": false, "payload": { "code": "CANCEL", "message": "cancelled" } });
+    client
+        .send_request("a", Payload::new(&cancel).unwrap(), STREAM_CANCEL)
+        .await
+        .unwrap();
+
+    assert_echo_runs(&mut client, "b").await;
+}
+
+#[tokio::test]
+async fn slots_are_released_when_procedures_time_out() {
+    let server = limited_server(|builder| {
+        builder.max_streams_per_session(1).procedure_timeout(
+            SERVICE,
+            "slow",
+            Duration::from_millis(20),
+        )
+    });
+    let mut client = connect_loopback(&server).await;
+
+    call(&mut client, "a", "slow").await;
+    let (stream_id, payload) = recv(&mut client).await;
+    assert_eq!(stream_id, "a");
+    assert_eq!(error_code(&payload), Some("CANCEL"));
+
+    assert_echo_runs(&mut client, "b").await;
+}
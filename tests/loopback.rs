@@ -0,0 +1,147 @@
+//! Services driven through the in-memory loopback transport
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::{
+    SERVICE, STREAM_CLOSED, STREAM_OPEN, TestHandler, connect_loopback, loopback_builder,
+    loopback_server,
+};
+use rapids::{
+    codecs::{BinaryCodec, NaiveCodec},
+    dispatch::RiverServer,
+    transport::{CloseReason, Frame},
+    types::{Control, Payload},
+};
+use serde_json::{Value, json};
+
+#[tokio::test]
+async fn rpc() {
+    let mut client = connect_loopback(&loopback_server()).await;
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new(&json!({ "hello": "world" })).unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.header.to, client.client_id());
+    assert_eq!(response.header.control_flags, STREAM_CLOSED);
+    assert_eq!(
+        response.payload.decode::<Value>().unwrap(),
+        json!({ "ok": true, "payload": { "hello": "world" } })
+    );
+}
+
+#[tokio::test]
+async fn stream() {
+    let mut client = connect_loopback(&loopback_server()).await;
+
+    client
+        .send_init(
+            "stream",
+            SERVICE,
+            "echoStream",
+            Payload::null(),
+            STREAM_OPEN,
+        )
+        .await
+        .unwrap();
+
+    for n in 0..3 {
+        client
+            .send_request("stream", Payload::new(&n).unwrap(), 0)
+            .await
+            .unwrap();
+
+        let response = client.recv().await.unwrap().unwrap();
+        assert_eq!(
+            response.payload.decode::<Value>().unwrap(),
+            json!({ "ok": true, "payload": n })
+        );
+    }
+
+    client
+        .send_control("stream", Control::Close, STREAM_CLOSED)
+        .await
+        .unwrap();
+
+    let close = client.recv().await.unwrap().unwrap();
+    assert_eq!(close.header.control_flags, STREAM_CLOSED);
+}
+
+#[tokio::test]
+async fn frames_use_server_codec() {
+    let server = Arc::new(RiverServer::new_with_heartbeat_interval(
+        NaiveCodec {},
+        TestHandler,
+        Duration::ZERO,
+    ));
+    let mut client = connect_loopback(&server).await;
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let frame = client.recv_frame().await.unwrap().unwrap();
+    let message: Value = serde_json::from_slice(&frame.data).unwrap();
+    assert!(!frame.text);
+    assert_eq!(message["streamId"], json!("rpc"));
+    assert_eq!(message["payload"], json!({ "ok": true, "payload": "hi" }));
+}
+
+#[tokio::test]
+async fn oversized_frame_closes_connection() {
+    let server = Arc::new(loopback_builder().max_frame_size(256).build().unwrap());
+    let mut client = connect_loopback(&server).await;
+
+    client
+        .send_frame(Frame::binary(vec![0; 512]))
+        .await
+        .unwrap();
+
+    assert!(client.recv_frame().await.is_none());
+    assert_eq!(client.close_reason(), Some(CloseReason::FrameTooLarge));
+}
+
+#[tokio::test]
+async fn heartbeats_are_received() {
+    let server = Arc::new(RiverServer::new_with_heartbeat_interval(
+        BinaryCodec {},
+        TestHandler,
+        Duration::from_millis(10),
+    ));
+    let mut client = connect_loopback(&server).await;
+
+    let heartbeat = client.recv().await.unwrap().unwrap();
+    assert_eq!(heartbeat.header.stream_id, "heartbeat");
+    assert!(matches!(
+        heartbeat.payload.decode::<Control>().unwrap(),
+        Control::Ack
+    ));
+}
+
+#[tokio::test]
+async fn servers_have_unique_ids() {
+    let first = loopback_server();
+    let second = loopback_server();
+    assert_ne!(first.server_id(), second.server_id());
+
+    let client = connect_loopback(&first).await;
+    assert_eq!(client.server_id(), Some(first.server_id()));
+}
@@ -0,0 +1,141 @@
+//! Picking a codec per connection with a `CodecNegotiation`
+
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{STREAM_CLOSED, STREAM_OPEN, TestClient, TestHandler, spawn_server};
+use rapids::{
+    codecs::{BinaryCodec, CborCodec, CodecNegotiation, NaiveCodec},
+    dispatch::RiverServer,
+    types::{Control, ExpectedSessionState, HandshakeRequest, ProtocolVersion},
+};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::{
+    Message, client::IntoClientRequest, protocol::frame::coding::CloseCode,
+};
+
+/// Serves a MessagePack server that lets clients pick JSON or CBOR
+async fn server(negotiation: CodecNegotiation) -> SocketAddr {
+    let negotiation = negotiation
+        .codec("json", NaiveCodec {})
+        .codec("cbor", CborCodec {});
+
+    let server = RiverServer::builder()
+        .codec(BinaryCodec {})
+        .handler(TestHandler)
+        .codec_negotiation(negotiation)
+        .build()
+        .unwrap();
+
+    spawn_server(server).await
+}
+
+async fn connect(
+    addr: SocketAddr,
+    path: &str,
+    subprotocols: Option<&str>,
+    codec: &str,
+) -> (TestClient, Option<String>) {
+    let mut request = format!("ws://{addr}{path}").into_client_request().unwrap();
+    if let Some(subprotocols) = subprotocols {
+        request
+            .headers_mut()
+            .insert("sec-websocket-protocol", subprotocols.parse().unwrap());
+    }
+
+    TestClient::connect_with(request, codec).await
+}
+
+/// Completes the handshake and an rpc, which only works if both sides use `codec`
+async fn assert_speaks(client: &mut TestClient) {
+    let response = client.handshake(ProtocolVersion::V2_0).await;
+    assert!(response.is_ok(), "{response:?}");
+
+    client
+        .send_init("rpc", "echo", json!("hi"), STREAM_OPEN | STREAM_CLOSED)
+        .await;
+
+    let response = client.recv().await;
+    assert_eq!(response.payload, json!({ "ok": true, "payload": "hi" }));
+}
+
+#[tokio::test]
+async fn subprotocols_select_a_codec() {
+    let addr = server(CodecNegotiation::new().subprotocols()).await;
+
+    // Unknown subprotocols are skipped, and the codec registered first wins
+    let (mut client, subprotocol) = connect(addr, "/", Some("xml, cbor, json"), "json").await;
+
+    assert_eq!(subprotocol.as_deref(), Some("json"));
+    assert_speaks(&mut client).await;
+}
+
+#[tokio::test]
+async fn clients_without_a_choice_get_the_default_codec() {
+    let addr = server(CodecNegotiation::new().subprotocols().query_param("codec")).await;
+
+    let (mut client, subprotocol) = connect(addr, "/", None, "msgpack").await;
+
+    assert_eq!(subprotocol, None);
+    assert_speaks(&mut client).await;
+}
+
+#[tokio::test]
+async fn query_param_overrides_the_default_codec() {
+    let addr = server(CodecNegotiation::new().query_param("codec")).await;
+
+    let (mut client, _) = connect(addr, "/delta?version=2&codec=json", None, "json").await;
+
+    assert_speaks(&mut client).await;
+}
+
+#[tokio::test]
+async fn subprotocols_win_over_the_query_param() {
+    let addr = server(CodecNegotiation::new().subprotocols().query_param("codec")).await;
+
+    let (mut client, subprotocol) = connect(addr, "/?codec=json", Some("cbor"), "cbor").await;
+
+    assert_eq!(subprotocol.as_deref(), Some("cbor"));
+    assert_speaks(&mut client).await;
+}
+
+#[tokio::test]
+async fn handshakes_are_sniffed() {
+    let addr = server(CodecNegotiation::new().sniff()).await;
+
+    for codec in ["json", "cbor", "msgpack"] {
+        let (mut client, _) = connect(addr, "/", None, codec).await;
+
+        assert_speaks(&mut client).await;
+    }
+}
+
+#[tokio::test]
+async fn handshakes_in_another_codec_are_rejected() {
+    let addr = server(CodecNegotiation::new().subprotocols().sniff()).await;
+
+    // The subprotocol is trusted over sniffing, so a MessagePack handshake
+    // is malformed JSON
+    let (mut client, subprotocol) = connect(addr, "/", Some("json"), "msgpack").await;
+    assert_eq!(subprotocol.as_deref(), Some("json"));
+
+    let request = Control::HandshakeRequest(HandshakeRequest {
+        protocol_version: ProtocolVersion::V2_0,
+        session_id: "session".to_string(),
+        expected_session_state: ExpectedSessionState::default(),
+        metadata: None,
+    });
+    client.send_control("handshake", request, 0).await;
+
+    loop {
+        match client.recv_frame().await {
+            Some(Message::Close(Some(frame))) => {
+                assert_eq!(frame.code, CloseCode::Protocol);
+                break;
+            }
+            Some(Message::Binary(_) | Message::Text(_)) => {}
+            message => panic!("Expected a close frame, got {message:?}"),
+        }
+    }
+}
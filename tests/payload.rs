@@ -0,0 +1,80 @@
+//! Payloads built from Rust types, as they appear on the wire
+
+use rapids::{
+    codecs::{BinaryCodec, CborCodec, NaiveCodec},
+    types::{Header, Payload, RequestInner, TransportCodec, TransportRequestMessage},
+    utils::ok_payload,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Event {
+    user: String,
+    count: u32,
+    kind: Kind,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum Kind {
+    Joined,
+}
+
+fn event() -> Event {
+    Event {
+        user: "a".to_string(),
+        count: 3,
+        kind: Kind::Joined,
+    }
+}
+
+/// Encodes a response carrying `event()` with `codec`
+fn encode(codec: &dyn TransportCodec) -> Vec<u8> {
+    codec
+        .encode_request(&TransportRequestMessage {
+            header: Header {
+                id: "id".to_string(),
+                from: "SERVER".to_string(),
+                to: "client".to_string(),
+                seq: 0,
+                ack: 0,
+                stream_id: "stream".to_string(),
+                control_flags: 0,
+            },
+            inner: RequestInner::Request {
+                payload: ok_payload(Payload::new(&event()).unwrap()),
+            },
+        })
+        .unwrap()
+}
+
+fn expected() -> Value {
+    json!({ "ok": true, "payload": { "user": "a", "count": 3, "kind": "joined" } })
+}
+
+#[test]
+fn struct_payloads_keep_field_names_in_json() {
+    let message: Value = serde_json::from_slice(&encode(&NaiveCodec {})).unwrap();
+    assert_eq!(message["payload"], expected());
+}
+
+#[test]
+fn struct_payloads_keep_field_names_in_msgpack() {
+    let message: Value = rmp_serde::from_slice(&encode(&BinaryCodec {})).unwrap();
+    assert_eq!(message["payload"], expected());
+}
+
+#[test]
+fn struct_payloads_keep_field_names_in_cbor() {
+    let message: Value = ciborium::from_reader(encode(&CborCodec {}).as_slice()).unwrap();
+    assert_eq!(message["payload"], expected());
+}
+
+#[test]
+fn payloads_decode_back_into_structs() {
+    assert_eq!(
+        Payload::new(&event()).unwrap().decode::<Event>().unwrap(),
+        event()
+    );
+}
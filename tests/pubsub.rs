@@ -0,0 +1,212 @@
+//! Subscriptions served through a `PubSub` topic registry
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use kanal::{AsyncReceiver, AsyncSender};
+use rapids::{
+    codecs::BinaryCodec,
+    dispatch::{RiverServer, ServiceHandler},
+    pubsub::{PubSub, SubscriptionEnd},
+    transport::loopback::LoopbackClient,
+    types::{
+        Control, IncomingMessage, OutgoingMessage, Payload, ProcedureRes, RPCMetadata, RawPayload,
+    },
+    utils,
+};
+use serde_json::{Value, json};
+use tokio::time;
+
+const STREAM_OPEN: i32 = 0b0010;
+const STREAM_CLOSED: i32 = 0b1000;
+
+/// Subscribes `feed.watch` streams to the topic named by their payload
+struct Feed {
+    pubsub: Arc<PubSub>,
+}
+
+impl ServiceHandler for Feed {
+    fn description(&self) -> HashMap<String, Vec<String>> {
+        HashMap::from([("feed".to_string(), vec!["watch".to_string()])])
+    }
+
+    async fn invoke_rpc(
+        &self,
+        _service: String,
+        _procedure: String,
+        metadata: RPCMetadata,
+        channel: AsyncSender<OutgoingMessage>,
+        payload: RawPayload,
+        recv: AsyncReceiver<IncomingMessage>,
+    ) {
+        let topic: String = payload.decode().unwrap();
+
+        let end = self
+            .pubsub
+            .subscribe(&topic, &metadata, channel.clone(), recv)
+            .await;
+
+        if end == SubscriptionEnd::Closed {
+            let close = utils::payload_to_msg(ProcedureRes::Close, &metadata, true, false);
+            channel.send(close).await.ok();
+        }
+    }
+}
+
+fn server() -> (Arc<PubSub>, Arc<RiverServer<Feed>>) {
+    let pubsub = Arc::new(PubSub::new());
+    let server = RiverServer::new_with_heartbeat_interval(
+        BinaryCodec {},
+        Feed {
+            pubsub: pubsub.clone(),
+        },
+        Duration::ZERO,
+    );
+
+    (pubsub, Arc::new(server))
+}
+
+async fn watch(server: &Arc<RiverServer<Feed>>, topic: &str) -> LoopbackClient {
+    let mut client = LoopbackClient::connect(server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init(
+            "watch",
+            "feed",
+            "watch",
+            Payload::new(topic).unwrap(),
+            STREAM_OPEN,
+        )
+        .await
+        .unwrap();
+
+    client
+}
+
+/// Waits until `topic` has `count` subscribers
+async fn wait_for_subscribers(pubsub: &PubSub, topic: &str, count: usize) {
+    time::timeout(Duration::from_secs(5), async {
+        while pubsub.subscriber_count(topic) != count {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("{topic} never had {count} subscribers"));
+}
+
+async fn recv(client: &mut LoopbackClient) -> (i32, Value) {
+    let message = client.recv().await.unwrap().unwrap();
+
+    (
+        message.header.control_flags,
+        message.payload.decode().unwrap(),
+    )
+}
+
+#[tokio::test]
+async fn published_values_reach_every_subscriber() {
+    let (pubsub, server) = server();
+    let mut first = watch(&server, "news").await;
+    let mut second = watch(&server, "news").await;
+    let mut other = watch(&server, "weather").await;
+    wait_for_subscribers(&pubsub, "news", 2).await;
+    wait_for_subscribers(&pubsub, "weather", 1).await;
+
+    assert_eq!(pubsub.publish("news", "hello").unwrap(), 2);
+    assert_eq!(pubsub.publish("sports", "nobody").unwrap(), 0);
+
+    for client in [&mut first, &mut second] {
+        assert_eq!(
+            recv(client).await,
+            (0, json!({ "ok": true, "payload": "hello" }))
+        );
+    }
+
+    assert_eq!(pubsub.publish("weather", "rain").unwrap(), 1);
+    assert_eq!(
+        recv(&mut other).await,
+        (0, json!({ "ok": true, "payload": "rain" }))
+    );
+}
+
+#[tokio::test]
+async fn closed_streams_are_unsubscribed() {
+    let (pubsub, server) = server();
+    let mut client = watch(&server, "news").await;
+    wait_for_subscribers(&pubsub, "news", 1).await;
+
+    client
+        .send_control("watch", Control::Close, STREAM_CLOSED)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        recv(&mut client).await,
+        (STREAM_CLOSED, json!({ "type": "CLOSE" }))
+    );
+    assert_eq!(pubsub.subscriber_count("news"), 0);
+    assert_eq!(pubsub.publish("news", "hello").unwrap(), 0);
+}
+
+#[tokio::test]
+async fn disconnected_clients_are_unsubscribed() {
+    let (pubsub, server) = server();
+    let mut client = watch(&server, "news").await;
+    wait_for_subscribers(&pubsub, "news", 1).await;
+
+    client.close().await.unwrap();
+
+    wait_for_subscribers(&pubsub, "news", 0).await;
+    assert_eq!(pubsub.publish("news", "hello").unwrap(), 0);
+}
+
+#[tokio::test]
+async fn full_subscribers_do_not_hold_up_others() {
+    let pubsub = Arc::new(PubSub::new());
+    let (slow, _slow_outgoing) = kanal::bounded_async(1);
+    let (fast, fast_outgoing) = kanal::unbounded_async();
+
+    let mut incoming = Vec::new();
+    for (stream_id, channel) in [("slow", slow), ("fast", fast)] {
+        let (send, recv) = kanal::unbounded_async();
+        incoming.push(send);
+        let metadata = RPCMetadata {
+            stream_id: stream_id.to_string(),
+            client_id: "client".to_string(),
+        };
+
+        let pubsub = pubsub.clone();
+        tokio::spawn(async move { pubsub.subscribe("news", &metadata, channel, recv).await });
+    }
+    wait_for_subscribers(&pubsub, "news", 2).await;
+
+    // Nothing reads the slow subscriber's queue, which is full after the first value
+    assert_eq!(pubsub.publish("news", &1).unwrap(), 2);
+    assert_eq!(pubsub.publish("news", &2).unwrap(), 1);
+    assert_eq!(pubsub.publish("news", &3).unwrap(), 1);
+
+    assert_eq!(fast_outgoing.len(), 3);
+    assert_eq!(pubsub.subscriber_count("news"), 2);
+}
+
+#[tokio::test]
+async fn aborted_subscriptions_are_pruned() {
+    let pubsub = Arc::new(PubSub::new());
+    let (channel, _outgoing) = kanal::unbounded_async();
+    let (_incoming, recv) = kanal::unbounded_async();
+    let metadata = RPCMetadata {
+        stream_id: "watch".to_string(),
+        client_id: "client".to_string(),
+    };
+
+    let task = tokio::spawn({
+        let pubsub = pubsub.clone();
+        async move { pubsub.subscribe("news", &metadata, channel, recv).await }
+    });
+    wait_for_subscribers(&pubsub, "news", 1).await;
+
+    task.abort();
+    assert!(task.await.unwrap_err().is_cancelled());
+
+    assert_eq!(pubsub.subscriber_count("news"), 0);
+}
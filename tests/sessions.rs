@@ -0,0 +1,426 @@
+//! Resuming sessions after a client reconnects
+
+mod common;
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::{SERVICE, STREAM_CLOSED, STREAM_OPEN, TestHandler, loopback_builder};
+use rapids::{
+    codecs::BinaryCodec,
+    dispatch::RiverServer,
+    sessions::{BufferedMessage, FileSessionStore, MemorySessionStore, SessionState, SessionStore},
+    transport::{
+        CloseReason, Frame, Transport,
+        client::RawClient,
+        loopback::{LoopbackClient, LoopbackTransport, loopback},
+    },
+    types::{HandshakeError, Payload, RiverResult},
+    utils::generate_id,
+};
+use serde_json::{Value, json};
+
+fn resumable_server(store: impl SessionStore + 'static) -> Arc<RiverServer<TestHandler>> {
+    Arc::new(
+        loopback_builder()
+            .session_grace_period(Duration::from_secs(5))
+            .session_store(store)
+            .build()
+            .unwrap(),
+    )
+}
+
+/// Calls `echo` and disconnects before reading the response
+async fn call_and_disconnect(client: &mut LoopbackClient) {
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    // The response is left unacknowledged, so the server has to resend it
+    assert!(client.recv_frame().await.unwrap().is_ok());
+    client.close().await.unwrap();
+}
+
+async fn assert_echo_replayed(client: &mut LoopbackClient) {
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(
+        response.payload.decode::<Value>().unwrap(),
+        json!({ "ok": true, "payload": "hi" })
+    );
+}
+
+/// A temporary directory for session files, removed when dropped
+struct StoreDir(PathBuf);
+
+impl StoreDir {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("rapids-sessions-{}", generate_id()));
+        std::fs::create_dir(&dir).unwrap();
+
+        Self(dir)
+    }
+
+    fn store(&self) -> FileSessionStore {
+        FileSessionStore::new(&self.0).unwrap()
+    }
+}
+
+impl Drop for StoreDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Waits until the session of `client` has been saved to `store`
+async fn wait_until_saved(store: &impl SessionStore, client: &LoopbackClient) {
+    let saved = async {
+        while store.load(client.session_id()).unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(5), saved)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn unacked_messages_are_resent() {
+    let server = resumable_server(MemorySessionStore::new());
+    let mut client = LoopbackClient::connect(&server);
+    call_and_disconnect(&mut client).await;
+
+    let response = client.reconnect_to(&server).await.unwrap();
+    assert!(response.is_ok(), "{response:?}");
+    assert_echo_replayed(&mut client).await;
+}
+
+#[tokio::test]
+async fn resumed_sessions_keep_their_procedures() {
+    let server = resumable_server(MemorySessionStore::new());
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init("sum", SERVICE, "sum", Payload::null(), STREAM_OPEN)
+        .await
+        .unwrap();
+    client
+        .send_request("sum", Payload::new(&json!({ "n": 2 })).unwrap(), 0)
+        .await
+        .unwrap();
+
+    // Messages are handled in order, so the procedure has the first number by the close
+    client.close().await.unwrap();
+
+    let response = client.reconnect_to(&server).await.unwrap();
+    assert!(response.is_ok(), "{response:?}");
+
+    // The stream opened before the disconnect still reaches the same procedure
+    client
+        .send_request("sum", Payload::new(&json!({ "n": 3 })).unwrap(), 0)
+        .await
+        .unwrap();
+    client
+        .send_request("sum", Payload::null(), STREAM_CLOSED)
+        .await
+        .unwrap();
+
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.stream_id, "sum");
+    assert_eq!(
+        response.payload.decode::<Value>().unwrap(),
+        json!({ "ok": true, "payload": { "total": 5 } })
+    );
+}
+
+#[tokio::test]
+async fn sessions_resume_on_another_server() {
+    let dir = StoreDir::new();
+    let first = resumable_server(dir.store());
+    let second = resumable_server(dir.store());
+
+    let mut client = LoopbackClient::connect(&first);
+    call_and_disconnect(&mut client).await;
+    wait_until_saved(&dir.store(), &client).await;
+
+    let response = client.reconnect_to(&second).await.unwrap();
+    assert!(response.is_ok(), "{response:?}");
+    assert_echo_replayed(&mut client).await;
+}
+
+/// A transport that fails to send anything after its first `sends` frames
+struct FailingTransport {
+    inner: LoopbackTransport,
+    sends: usize,
+}
+
+impl Transport for FailingTransport {
+    async fn recv(&mut self) -> Option<anyhow::Result<Frame>> {
+        self.inner.recv().await
+    }
+
+    async fn send(&mut self, frame: Frame) -> anyhow::Result<()> {
+        anyhow::ensure!(self.sends > 0, "connection reset");
+        self.sends -= 1;
+        self.inner.send(frame).await
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> anyhow::Result<()> {
+        self.inner.close(reason).await
+    }
+}
+
+#[tokio::test]
+async fn sessions_resume_after_failed_sends() {
+    let server = resumable_server(MemorySessionStore::new());
+    let (client_end, server_end) = loopback();
+    let transport = FailingTransport {
+        inner: server_end,
+        sends: 1,
+    };
+    tokio::spawn(
+        server
+            .clone()
+            .serve_transport(transport, "failing".to_string()),
+    );
+
+    // Only the handshake response makes it to the client
+    let mut client = RawClient::new(client_end, Arc::new(BinaryCodec {}));
+    assert!(client.handshake().await.unwrap().is_ok());
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+    assert!(client.recv_frame().await.is_none());
+
+    let response = client.reconnect_to(&server).await.unwrap();
+    assert!(response.is_ok(), "{response:?}");
+    assert_echo_replayed(&mut client).await;
+}
+
+/// A connection the server never sees close, like one whose client vanished
+struct HalfOpenTransport(LoopbackTransport);
+
+impl Transport for HalfOpenTransport {
+    async fn recv(&mut self) -> Option<anyhow::Result<Frame>> {
+        match self.0.recv().await {
+            None => std::future::pending().await,
+            received => received,
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self.0.send(frame).await
+    }
+
+    async fn close(&mut self, reason: CloseReason) -> anyhow::Result<()> {
+        self.0.close(reason).await
+    }
+}
+
+#[tokio::test]
+async fn sessions_resume_while_still_connected() {
+    let server = resumable_server(MemorySessionStore::new());
+    let (client_end, server_end) = loopback();
+    tokio::spawn(
+        server
+            .clone()
+            .serve_transport(HalfOpenTransport(server_end), "half open".to_string()),
+    );
+
+    let mut client = RawClient::new(client_end, Arc::new(BinaryCodec {}));
+    assert!(client.handshake().await.unwrap().is_ok());
+    client
+        .send_init("sum", SERVICE, "sum", Payload::null(), STREAM_OPEN)
+        .await
+        .unwrap();
+    client
+        .send_request("sum", Payload::new(&json!({ "n": 2 })).unwrap(), 0)
+        .await
+        .unwrap();
+
+    // Messages are handled in order, so the sum has seen its first number once this returns
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+    assert_echo_replayed(&mut client).await;
+
+    let response = client.reconnect_to(&server).await.unwrap();
+    assert!(response.is_ok(), "{response:?}");
+
+    client
+        .send_request("sum", Payload::new(&json!({ "n": 3 })).unwrap(), 0)
+        .await
+        .unwrap();
+    client
+        .send_request("sum", Payload::null(), STREAM_CLOSED)
+        .await
+        .unwrap();
+
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.stream_id, "sum");
+    assert_eq!(
+        response.payload.decode::<Value>().unwrap(),
+        json!({ "ok": true, "payload": { "total": 5 } })
+    );
+}
+
+#[tokio::test]
+async fn unknown_sessions_are_rejected() {
+    let mut client = LoopbackClient::connect(&resumable_server(MemorySessionStore::new()));
+    call_and_disconnect(&mut client).await;
+
+    let other = resumable_server(MemorySessionStore::new());
+    match client.reconnect_to(&other).await.unwrap() {
+        RiverResult::Err { code, .. } => {
+            assert!(matches!(code, HandshakeError::SessionStateMismatch));
+        }
+        RiverResult::Ok(response) => panic!("session was resumed: {response:?}"),
+    }
+}
+
+#[tokio::test]
+async fn sessions_end_once_their_buffer_is_full() {
+    let server = Arc::new(
+        loopback_builder()
+            .session_grace_period(Duration::from_secs(5))
+            .session_buffer_limit(3, 1024 * 1024)
+            .build()
+            .unwrap(),
+    );
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    // The client never sends anything after the countdown, so never acknowledges it
+    client
+        .send_init(
+            "countdown",
+            SERVICE,
+            "countdown",
+            Payload::new(&10).unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let mut received = 0;
+    while let Some(message) = client.recv().await.unwrap() {
+        assert_eq!(message.header.stream_id, "countdown");
+        received += 1;
+    }
+    assert_eq!(received, 4);
+    assert_eq!(
+        client.transport().close_reason(),
+        Some(CloseReason::SessionBufferFull)
+    );
+
+    // The session is gone rather than resumable
+    match client.reconnect_to(&server).await.unwrap() {
+        RiverResult::Err { code, .. } => {
+            assert!(matches!(code, HandshakeError::SessionStateMismatch));
+        }
+        RiverResult::Ok(response) => panic!("session was resumed: {response:?}"),
+    }
+}
+
+/// A store that blocks its thread on every load
+struct SlowStore(MemorySessionStore);
+
+impl SessionStore for SlowStore {
+    fn load(&self, session_id: &str) -> anyhow::Result<Option<SessionState>> {
+        std::thread::sleep(Duration::from_millis(500));
+        self.0.load(session_id)
+    }
+
+    fn save(&self, state: &SessionState) -> anyhow::Result<()> {
+        self.0.save(state)
+    }
+
+    fn remove(&self, session_id: &str) -> anyhow::Result<()> {
+        self.0.remove(session_id)
+    }
+}
+
+#[tokio::test]
+async fn slow_stores_do_not_block_other_sessions() {
+    let server = resumable_server(SlowStore(MemorySessionStore::new()));
+    let mut client = LoopbackClient::connect(&server);
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    // Keeps the store busy while the first client calls a procedure
+    let start = Instant::now();
+    let mut other = LoopbackClient::connect(&server);
+    let handshake = tokio::spawn(async move { other.handshake().await.unwrap().is_ok() });
+    tokio::task::yield_now().await;
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new("hi").unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+    assert_echo_replayed(&mut client).await;
+    assert!(start.elapsed() < Duration::from_millis(250));
+
+    assert!(handshake.await.unwrap());
+}
+
+#[test]
+fn file_store_round_trips() {
+    let dir = StoreDir::new();
+    let store = dir.store();
+    let state = SessionState {
+        session_id: "session".to_string(),
+        client_id: "client".to_string(),
+        next_sent_seq: 3,
+        next_expected_seq: 2,
+        unacked: vec![BufferedMessage {
+            seq: 2,
+            data: vec![0x81, 0xa2, 0x6f, 0x6b],
+        }],
+    };
+
+    assert_eq!(store.load("session").unwrap(), None);
+    store.save(&state).unwrap();
+    assert_eq!(store.load("session").unwrap(), Some(state));
+
+    // Messages are stored as base64 rather than arrays of numbers
+    let stored: Value =
+        serde_json::from_slice(&std::fs::read(dir.0.join("session.json")).unwrap()).unwrap();
+    assert_eq!(stored["unacked"][0]["data"], "gaJvaw==");
+    store.remove("session").unwrap();
+    assert_eq!(store.load("session").unwrap(), None);
+
+    assert!(store.load("../session").is_err());
+}
@@ -0,0 +1,86 @@
+//! A host calling procedures of a plugin over stdio
+//!
+//! This test has no harness, as libtest writes to stdout. The binary spawns
+//! itself as the plugin, which is told apart by an environment variable.
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::{SERVICE, STREAM_CLOSED, STREAM_OPEN, TestHandler};
+use rapids::{
+    codecs::BinaryCodec,
+    dispatch::RiverServer,
+    transport::{Frame, client::RawClient, stdio::ChildTransport},
+    types::Payload,
+};
+use serde_json::{Value, json};
+use tokio::{process::Command, time};
+
+const PLUGIN_ENV: &str = "RAPIDS_STDIO_PLUGIN";
+const MAX_FRAME_SIZE: usize = 1024;
+
+async fn plugin() {
+    let server = Arc::new(
+        RiverServer::builder()
+            .codec(BinaryCodec {})
+            .handler(TestHandler)
+            .heartbeat_interval(Duration::ZERO)
+            .max_frame_size(MAX_FRAME_SIZE)
+            .build()
+            .unwrap(),
+    );
+
+    server.serve_stdio().await;
+}
+
+async fn host() {
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    command.env(PLUGIN_ENV, "1");
+
+    let transport = ChildTransport::spawn(&mut command).unwrap();
+    let mut client = RawClient::new(transport, Arc::new(BinaryCodec {}));
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new(&json!({ "from": "host" })).unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.control_flags, STREAM_CLOSED);
+    assert_eq!(
+        response.payload.decode::<Value>().unwrap(),
+        json!({ "ok": true, "payload": { "from": "host" } })
+    );
+
+    // The plugin stops serving once its frame limit is exceeded
+    client
+        .send_frame(Frame::binary(vec![0; MAX_FRAME_SIZE + 1]))
+        .await
+        .unwrap();
+    assert!(!matches!(client.recv().await, Ok(Some(_))));
+}
+
+fn main() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    if std::env::var_os(PLUGIN_ENV).is_some() {
+        runtime.block_on(plugin());
+        return;
+    }
+
+    runtime.block_on(async {
+        time::timeout(Duration::from_secs(10), host())
+            .await
+            .expect("Timed out talking to plugin");
+    });
+
+    println!("stdio host called plugin procedure: ok");
+}
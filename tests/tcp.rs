@@ -0,0 +1,166 @@
+//! Services served over the raw TCP transport
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use common::{SERVICE, STREAM_CLOSED, STREAM_OPEN, TestHandler};
+use rapids::{
+    codecs::BinaryCodec,
+    dispatch::RiverServer,
+    transport::{
+        Transport,
+        client::RawClient,
+        stream::{DEFAULT_MAX_FRAME_SIZE, StreamTransport},
+        tcp::TcpTransport,
+    },
+    types::{
+        Codec, Control, ExpectedSessionState, HandshakeRequest, Header, Payload, ProtocolVersion,
+        TransportCodec, TransportControlMessage,
+    },
+    utils::generate_id,
+};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+async fn spawn_server() -> std::net::SocketAddr {
+    let server = Arc::new(RiverServer::new_with_heartbeat_interval(
+        BinaryCodec {},
+        TestHandler,
+        Duration::ZERO,
+    ));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.serve_tcp(listener));
+
+    addr
+}
+
+#[tokio::test]
+async fn rpc() {
+    let transport = TcpTransport::connect(spawn_server().await).await.unwrap();
+    let mut client = RawClient::new(transport, Arc::new(BinaryCodec {}));
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new(&json!({ "hello": "world" })).unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.stream_id, "rpc");
+    assert_eq!(response.header.control_flags, STREAM_CLOSED);
+    assert_eq!(
+        response.payload.decode::<Value>().unwrap(),
+        json!({ "ok": true, "payload": { "hello": "world" } })
+    );
+}
+
+#[tokio::test]
+async fn frames_split_across_writes() {
+    let stream = TcpStream::connect(spawn_server().await).await.unwrap();
+    let (reader, mut writer) = stream.into_split();
+    let mut transport = StreamTransport::new(reader, tokio::io::sink());
+
+    let handshake = TransportControlMessage {
+        header: Header {
+            id: generate_id(),
+            from: "client".to_string(),
+            to: "SERVER".to_string(),
+            seq: 0,
+            ack: 0,
+            stream_id: generate_id(),
+            control_flags: 0,
+        },
+        payload: Control::HandshakeRequest(HandshakeRequest {
+            protocol_version: ProtocolVersion::V2_0,
+            session_id: generate_id(),
+            expected_session_state: ExpectedSessionState::default(),
+            metadata: None,
+        }),
+    };
+    let data = BinaryCodec {}.encode_to_vec(&handshake).unwrap();
+    let len = u32::try_from(data.len()).unwrap();
+
+    for byte in len.to_be_bytes().into_iter().chain(data) {
+        writer.write_all(&[byte]).await.unwrap();
+        writer.flush().await.unwrap();
+    }
+
+    let frame = transport.recv().await.unwrap().unwrap();
+    let response = BinaryCodec {}.decode_control(&frame.data).unwrap();
+    assert!(matches!(response.payload, Control::HandshakeResponse(_)));
+}
+
+#[tokio::test]
+async fn oversized_length_prefix_is_rejected() {
+    let mut transport =
+        StreamTransport::new(&[0xff; 4][..], tokio::io::sink()).with_max_frame_size(16);
+
+    assert!(transport.recv().await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn length_prefixes_are_limited_by_default() {
+    let (mut client, server) = tokio::io::duplex(64);
+    let mut transport = StreamTransport::new(server, tokio::io::sink());
+
+    let len = u32::try_from(DEFAULT_MAX_FRAME_SIZE + 1).unwrap();
+    client.write_all(&len.to_be_bytes()).await.unwrap();
+
+    // Rejected from the prefix alone, without waiting for the frame
+    let result = time::timeout(Duration::from_secs(5), transport.recv())
+        .await
+        .unwrap();
+    assert!(result.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn clients_report_transport_errors() {
+    let (mut server, client) = tokio::io::duplex(64);
+    let transport = StreamTransport::new(client, tokio::io::sink()).with_max_frame_size(64);
+    let mut client = RawClient::new(transport, Arc::new(BinaryCodec {}));
+
+    server.write_all(&1024u32.to_be_bytes()).await.unwrap();
+    assert!(matches!(client.recv_frame().await, Some(Err(_))));
+
+    server.write_all(&1024u32.to_be_bytes()).await.unwrap();
+    assert!(client.recv().await.is_err());
+}
+
+#[tokio::test]
+async fn server_frame_limit_applies_to_tcp_connections() {
+    let server = Arc::new(
+        RiverServer::builder()
+            .codec(BinaryCodec {})
+            .handler(TestHandler)
+            .heartbeat_interval(Duration::ZERO)
+            .max_frame_size(64)
+            .build()
+            .unwrap(),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.serve_tcp(listener));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&1024u32.to_be_bytes()).await.unwrap();
+
+    // The server hangs up without waiting for the rest of the frame
+    let mut buf = [0; 16];
+    let read = time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
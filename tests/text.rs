@@ -0,0 +1,111 @@
+//! WebSocket text frames, as sent by browser debugging tools
+
+mod common;
+
+use std::time::Duration;
+
+use common::{STREAM_CLOSED, STREAM_OPEN, TestClient, TestHandler, spawn_server};
+use rapids::{
+    codecs::{self, NaiveCodec},
+    dispatch::RiverServer,
+    types::{
+        Control, ExpectedSessionState, HandshakeRequest, Header, ProtocolVersion, RequestInner,
+        TransportCodec, TransportControlMessage, TransportRequestMessage,
+    },
+};
+use serde_json::{Value, json};
+use tokio_tungstenite::tungstenite::{Message, protocol::frame::coding::CloseCode};
+
+fn header(stream_id: &str, seq: i32, control_flags: i32) -> Header {
+    Header {
+        id: format!("message-{seq}"),
+        from: TestClient::ID.to_string(),
+        to: "SERVER".to_string(),
+        seq,
+        ack: 0,
+        stream_id: stream_id.to_string(),
+        control_flags,
+    }
+}
+
+/// A JSON handshake request
+fn handshake() -> String {
+    let message = TransportControlMessage {
+        header: header("handshake", 0, 0),
+        payload: Control::HandshakeRequest(HandshakeRequest {
+            protocol_version: ProtocolVersion::V2_0,
+            session_id: "session".to_string(),
+            expected_session_state: ExpectedSessionState::default(),
+            metadata: None,
+        }),
+    };
+
+    String::from_utf8(NaiveCodec {}.encode_control(&message).unwrap()).unwrap()
+}
+
+async fn connect(codec: &str) -> TestClient {
+    // Heartbeats would race the frames the tests expect
+    let server = RiverServer::new_with_heartbeat_interval(
+        codecs::by_name(codec).unwrap(),
+        TestHandler,
+        Duration::ZERO,
+    );
+
+    TestClient::connect(spawn_server(server).await, codec).await
+}
+
+/// Receives the next text frame as JSON
+async fn recv_text(client: &mut TestClient) -> Value {
+    match client.recv_frame().await {
+        Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+        message => panic!("Expected a text frame, got {message:?}"),
+    }
+}
+
+#[tokio::test]
+async fn json_clients_can_use_text_frames() {
+    let mut client = connect("json").await;
+
+    client.send_text(handshake()).await;
+
+    let response = recv_text(&mut client).await;
+    assert_eq!(response["payload"]["type"], json!("HANDSHAKE_RESP"));
+    assert_eq!(response["payload"]["status"]["ok"], json!(true));
+
+    let request = TransportRequestMessage {
+        header: header("rpc", 1, STREAM_OPEN | STREAM_CLOSED),
+        inner: RequestInner::Init {
+            service_name: "test".to_string(),
+            procedure_name: "echo".to_string(),
+            payload: json!("hi").into(),
+        },
+    };
+    let request = NaiveCodec {}.encode_request(&request).unwrap();
+    client.send_text(String::from_utf8(request).unwrap()).await;
+
+    // Responses mirror the frame type of the handshake
+    let response = recv_text(&mut client).await;
+    assert_eq!(response["streamId"], json!("rpc"));
+    assert_eq!(response["payload"], json!({ "ok": true, "payload": "hi" }));
+}
+
+async fn binary_codecs_reject_text_frames(codec: &str) {
+    let mut client = connect(codec).await;
+
+    client.send_text(handshake()).await;
+
+    match client.recv_frame().await {
+        Some(Message::Close(Some(frame))) => assert_eq!(frame.code, CloseCode::Unsupported),
+        message => panic!("Expected a close frame, got {message:?}"),
+    }
+}
+
+#[tokio::test]
+async fn msgpack_rejects_text_frames() {
+    binary_codecs_reject_text_frames("msgpack").await;
+}
+
+#[tokio::test]
+async fn cbor_rejects_text_frames() {
+    binary_codecs_reject_text_frames("cbor").await;
+}
@@ -0,0 +1,103 @@
+//! Services served over Unix domain sockets
+#![cfg(unix)]
+
+mod common;
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use common::{SERVICE, STREAM_CLOSED, STREAM_OPEN, TestHandler};
+use rapids::{
+    codecs::BinaryCodec,
+    dispatch::RiverServer,
+    transport::{client::RawClient, unix::UnixTransport},
+    types::Payload,
+    utils::generate_id,
+};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    time,
+};
+
+/// A temporary directory for sockets, removed even if the test panics
+struct SocketDir(PathBuf);
+
+impl SocketDir {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("rapids-{}", generate_id()));
+        std::fs::create_dir(&dir).unwrap();
+
+        Self(dir)
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.0.join("river.sock")
+    }
+}
+
+impl Drop for SocketDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+#[tokio::test]
+async fn rpc() {
+    let dir = SocketDir::new();
+    let path = dir.socket();
+    let server = Arc::new(RiverServer::new_with_heartbeat_interval(
+        BinaryCodec {},
+        TestHandler,
+        Duration::ZERO,
+    ));
+    tokio::spawn(server.serve_unix(UnixListener::bind(&path).unwrap()));
+
+    let transport = UnixTransport::connect(&path).await.unwrap();
+    let mut client = RawClient::new(transport, Arc::new(BinaryCodec {}));
+    assert!(client.handshake().await.unwrap().is_ok());
+
+    client
+        .send_init(
+            "rpc",
+            SERVICE,
+            "echo",
+            Payload::new(&json!([1, 2, 3])).unwrap(),
+            STREAM_OPEN | STREAM_CLOSED,
+        )
+        .await
+        .unwrap();
+
+    let response = client.recv().await.unwrap().unwrap();
+    assert_eq!(response.header.control_flags, STREAM_CLOSED);
+    assert_eq!(
+        response.payload.decode::<Value>().unwrap(),
+        json!({ "ok": true, "payload": [1, 2, 3] })
+    );
+}
+
+#[tokio::test]
+async fn server_frame_limit_applies_to_unix_connections() {
+    let dir = SocketDir::new();
+    let path = dir.socket();
+    let server = Arc::new(
+        RiverServer::builder()
+            .codec(BinaryCodec {})
+            .handler(TestHandler)
+            .heartbeat_interval(Duration::ZERO)
+            .max_frame_size(64)
+            .build()
+            .unwrap(),
+    );
+    tokio::spawn(server.serve_unix(UnixListener::bind(&path).unwrap()));
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream.write_all(&1024u32.to_be_bytes()).await.unwrap();
+
+    // The server hangs up without waiting for the rest of the frame
+    let mut buf = [0; 16];
+    let read = time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
@@ -0,0 +1,118 @@
+//! Rejecting WebSocket requests before they are upgraded
+
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{http::StatusCode, response::IntoResponse};
+use common::{TestHandler, spawn_server};
+use rapids::{codecs::BinaryCodec, dispatch::RiverServer};
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+async fn server() -> SocketAddr {
+    spawn_server(
+        RiverServer::builder()
+            .codec(BinaryCodec {})
+            .handler(TestHandler)
+            .on_upgrade(|request| async move {
+                if request.origin() != Some("https://example.com") {
+                    return Err(StatusCode::FORBIDDEN.into_response());
+                }
+
+                // Stands in for looking the session up somewhere else
+                tokio::time::sleep(Duration::from_millis(10)).await;
+
+                match request.cookie("session") {
+                    Some("valid") => Ok(()),
+                    _ => Err(StatusCode::UNAUTHORIZED.into_response()),
+                }
+            })
+            .build()
+            .unwrap(),
+    )
+    .await
+}
+
+/// Connects with the given headers, returning the HTTP status of a rejection
+async fn connect(addr: SocketAddr, headers: &[(&'static str, &str)]) -> Option<StatusCode> {
+    let mut request = format!("ws://{addr}/").into_client_request().unwrap();
+    for (name, value) in headers {
+        request.headers_mut().insert(*name, value.parse().unwrap());
+    }
+
+    match tokio_tungstenite::connect_async(request).await {
+        Ok(_) => None,
+        Err(tungstenite::Error::Http(response)) => {
+            Some(response.status().as_u16().try_into().unwrap())
+        }
+        Err(err) => panic!("Unexpected error: {err}"),
+    }
+}
+
+#[tokio::test]
+async fn accepted_requests_are_upgraded() {
+    let status = connect(
+        server().await,
+        &[
+            ("origin", "https://example.com"),
+            ("cookie", "theme=dark; session=valid"),
+        ],
+    )
+    .await;
+
+    assert_eq!(status, None);
+}
+
+#[tokio::test]
+async fn requests_are_rejected_with_the_hook_response() {
+    let addr = server().await;
+
+    assert_eq!(
+        connect(addr, &[("cookie", "session=valid")]).await,
+        Some(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(
+        connect(
+            addr,
+            &[
+                ("origin", "https://example.com"),
+                ("cookie", "session=expired")
+            ]
+        )
+        .await,
+        Some(StatusCode::UNAUTHORIZED)
+    );
+}
+
+#[tokio::test]
+async fn hooks_see_the_request_uri() {
+    let addr = spawn_server(
+        RiverServer::builder()
+            .codec(BinaryCodec {})
+            .handler(TestHandler)
+            .on_upgrade(|request| async move {
+                match request.query() {
+                    Some("token=secret") if request.parts.uri.path() == "/river" => Ok(()),
+                    _ => Err(StatusCode::UNAUTHORIZED.into_response()),
+                }
+            })
+            .build()
+            .unwrap(),
+    )
+    .await;
+
+    let connect = |path: &str| {
+        let request = format!("ws://{addr}{path}").into_client_request().unwrap();
+        async move {
+            match tokio_tungstenite::connect_async(request).await {
+                Ok(_) => None,
+                Err(tungstenite::Error::Http(response)) => Some(response.status().as_u16()),
+                Err(err) => panic!("Unexpected error: {err}"),
+            }
+        }
+    };
+
+    assert_eq!(connect("/river?token=secret").await, None);
+    assert_eq!(connect("/river?token=wrong").await, Some(401));
+    assert_eq!(connect("/other?token=secret").await, Some(401));
+}
@@ -0,0 +1,160 @@
+//! Known encodings of River messages, as other implementations send them
+
+mod common;
+
+use std::sync::Arc;
+
+use common::hex;
+use rapids::{
+    codecs::{BinaryCodec, CborCodec},
+    types::{
+        Codec, Control, ExpectedSessionState, HandshakeRequest, Header, Payload, ProtocolVersion,
+        RawPayload, RequestInner, TransportControlMessage, TransportRequestMessage,
+    },
+    utils::ok_payload,
+};
+use serde_json::{Value, json};
+
+fn header(
+    id: &str,
+    from: &str,
+    to: &str,
+    seq: i32,
+    ack: i32,
+    stream_id: &str,
+    control_flags: i32,
+) -> Header {
+    Header {
+        id: id.to_string(),
+        from: from.to_string(),
+        to: to.to_string(),
+        seq,
+        ack,
+        stream_id: stream_id.to_string(),
+        control_flags,
+    }
+}
+
+// The TypeScript implementation encodes MessagePack with `ignoreUndefined`,
+// writing keys in insertion order: the header first, in the order of its
+// handshake helpers, then the rest of the message.
+
+/// A handshake request without metadata
+const MSGPACK_HANDSHAKE: &str = include_str!("fixtures/handshake.msgpack.hex");
+
+/// An rpc `Init` for `test.echo`
+const MSGPACK_REQUEST: &str = include_str!("fixtures/request.msgpack.hex");
+
+/// The response closing that rpc
+const MSGPACK_RESPONSE: &str = include_str!("fixtures/response.msgpack.hex");
+
+#[test]
+fn msgpack_handshake_matches_fixture() {
+    let message = TransportControlMessage {
+        header: header("hs-1", "client-1", "SERVER", 0, 0, "hs-stream", 0),
+        payload: Control::HandshakeRequest(HandshakeRequest {
+            protocol_version: ProtocolVersion::V2_0,
+            session_id: "session-1".to_string(),
+            expected_session_state: ExpectedSessionState::default(),
+            metadata: None,
+        }),
+    };
+
+    assert_eq!(
+        BinaryCodec {}.encode_to_vec(&message).unwrap(),
+        hex(MSGPACK_HANDSHAKE)
+    );
+
+    let decoded: TransportControlMessage = BinaryCodec {}
+        .decode_slice(&hex(MSGPACK_HANDSHAKE))
+        .unwrap();
+    let Control::HandshakeRequest(request) = decoded.payload else {
+        panic!("Expected a handshake request");
+    };
+    assert_eq!(request.session_id, "session-1");
+    assert_eq!(request.metadata, None);
+}
+
+#[test]
+fn msgpack_request_matches_fixture() {
+    let message = TransportRequestMessage {
+        header: header("req-1", "client-1", "SERVER", 1, 1, "stream-1", 10),
+        inner: RequestInner::Init {
+            service_name: "test".to_string(),
+            procedure_name: "echo".to_string(),
+            payload: Payload::new(&json!({ "hello": "world" })).unwrap(),
+        },
+    };
+
+    assert_eq!(
+        BinaryCodec {}.encode_to_vec(&message).unwrap(),
+        hex(MSGPACK_REQUEST)
+    );
+
+    let data = hex(MSGPACK_REQUEST);
+    let frame = Codec::decode_frame(&BinaryCodec {}, &data).unwrap();
+    assert_eq!(frame.header.stream_id, "stream-1");
+    assert_eq!(frame.service_name.as_deref(), Some("test"));
+    assert_eq!(frame.procedure_name.as_deref(), Some("echo"));
+
+    let payload = RawPayload::new(frame.payload.into_owned(), Arc::new(BinaryCodec {}));
+    assert_eq!(
+        payload.decode::<Value>().unwrap(),
+        json!({ "hello": "world" })
+    );
+}
+
+#[test]
+fn msgpack_response_matches_fixture() {
+    let message = TransportRequestMessage {
+        header: header("resp-1", "SERVER", "client-1", 1, 2, "stream-1", 8),
+        inner: RequestInner::Request {
+            payload: ok_payload(Payload::new(&json!({ "hello": "world" })).unwrap()),
+        },
+    };
+
+    assert_eq!(
+        BinaryCodec {}.encode_to_vec(&message).unwrap(),
+        hex(MSGPACK_RESPONSE)
+    );
+}
+
+/// An rpc `Init` for `test.echo` as sent by a JavaScript client, which
+/// encodes CBOR with definite lengths and the shortest integers:
+///
+/// ```json
+/// { "id": "Jx2yGcU5Wd", "from": "client-1", "to": "SERVER", "seq": 3, "ack": 2,
+///   "streamId": "stream-1", "controlFlags": 10, "serviceName": "test",
+///   "procedureName": "echo", "payload": { "hello": "world" } }
+/// ```
+const CBOR_INIT: &str = include_str!("fixtures/init.cbor.hex");
+
+#[test]
+fn cbor_init_from_javascript_client_is_decoded() {
+    let data = hex(CBOR_INIT);
+    let frame = Codec::decode_frame(&CborCodec {}, &data).unwrap();
+
+    assert_eq!(frame.header.id, "Jx2yGcU5Wd");
+    assert_eq!(frame.header.from, "client-1");
+    assert_eq!(frame.header.to, "SERVER");
+    assert_eq!(frame.header.seq, 3);
+    assert_eq!(frame.header.ack, 2);
+    assert_eq!(frame.header.stream_id, "stream-1");
+    assert_eq!(frame.header.control_flags, 10);
+    assert_eq!(frame.service_name.as_deref(), Some("test"));
+    assert_eq!(frame.procedure_name.as_deref(), Some("echo"));
+
+    let payload = RawPayload::new(frame.payload.into_owned(), Arc::new(CborCodec {}));
+    assert_eq!(
+        payload.decode::<Value>().unwrap(),
+        json!({ "hello": "world" })
+    );
+}
+
+#[test]
+fn cbor_trailing_bytes_are_rejected() {
+    let mut data = hex(CBOR_INIT);
+    data.push(0);
+
+    assert!(Codec::decode_frame(&CborCodec {}, &data).is_err());
+}